@@ -0,0 +1,133 @@
+use dashmap::DashMap;
+use ibig::UBig;
+
+use super::packet::channel;
+
+/// Datagrams routed to one task, bounded so a slow task pushes back on the bus.
+pub(crate) type Inbox = flume::Receiver<channel::Datagram>;
+
+/// Deliver each datagram only to the tasks that own it.
+///
+/// The link and the heartbeat have fixed inboxes, sessions and streams
+/// register theirs by id and are dropped once the task is gone.
+pub(crate) struct Dispatcher {
+	capacity: usize,
+	link: flume::Sender<channel::Datagram>,
+	health: flume::Sender<channel::Datagram>,
+	sessions: DashMap<UBig, flume::Sender<channel::Datagram>>,
+	streams: DashMap<(UBig, UBig), flume::Sender<channel::Datagram>>
+}
+
+impl Dispatcher {
+	/// Get the dispatcher along with the inboxes of the link and the heartbeat.
+	pub fn new(capacity: usize) -> (Self, Inbox, Inbox) {
+		let (link, link_inbox) = flume::bounded(capacity);
+		let (health, health_inbox) = flume::bounded(capacity);
+
+		let dispatcher = Self {
+			capacity,
+			link,
+			health,
+			sessions: DashMap::new(),
+			streams: DashMap::new()
+		};
+
+		(dispatcher, link_inbox, health_inbox)
+	}
+
+	/// Register the inbox of a session, before anything for it can arrive.
+	pub fn session(&self, session_id: UBig) -> Inbox {
+		let (sender, inbox) = flume::bounded(self.capacity);
+		self.sessions.insert(session_id, sender);
+		inbox
+	}
+
+	/// Register the inbox of a stream, before anything for it can arrive.
+	pub fn stream(&self, session_id: UBig, stream_id: UBig) -> Inbox {
+		let (sender, inbox) = flume::bounded(self.capacity);
+		self.streams.insert((session_id, stream_id), sender);
+		inbox
+	}
+
+	/// Drop the inboxes of a session and its streams once it no longer exists.
+	pub fn forget_session(&self, session_id: &UBig) {
+		self.sessions.remove(session_id);
+		self.streams.retain(|(owner, _), _| owner != session_id);
+	}
+
+	/// Hand `data` to its owners, waiting while their inbox is full.
+	pub async fn dispatch(&self, data: channel::Datagram) {
+		use channel::{Event as WrapEvent, link::Event as LinkEvent, stream::Event as StreamEvent};
+
+		let session_id = data.id.session.clone();
+		let stream_id = data.id.stream.clone();
+
+		match &data.event {
+			WrapEvent::Link(LinkEvent::Health(_)) => {
+				let _ = self.health.send_async(data.clone()).await;
+				let _ = self.link.send_async(data).await;
+			},
+
+			// 会话事件由 link 维护状态，会话和它的流据此结束或恢复，
+			// 先交给会话和流，link 处理结束时会注销它们
+			WrapEvent::Session(_) | WrapEvent::Link(LinkEvent::SessionAck(_)) => {
+				if let Some(session_id) = session_id {
+					self.to_session(session_id, data.clone()).await;
+				}
+
+				let _ = self.link.send_async(data).await;
+			},
+
+			// 本地发出的流事件交给 link 发送，Clear 同时让流自己结束
+			WrapEvent::Link(LinkEvent::StreamAck(event)) => {
+				if let (StreamEvent::Clear(_), Some(session_id), Some(stream_id)) = (event, session_id, stream_id) {
+					deliver(&self.streams, (session_id, stream_id), data.clone()).await;
+				}
+
+				let _ = self.link.send_async(data).await;
+			},
+
+			// 对方发来的流事件，还没有读取端的交给会话，
+			// 重发的 Open 也由会话回应
+			WrapEvent::Stream(event) => {
+				let Some(session_id) = session_id else {
+					return;
+				};
+
+				match stream_id {
+					Some(stream_id) if !matches!(event, StreamEvent::Open { .. })
+					&& self.streams.contains_key(&(session_id.clone(), stream_id.clone())) => {
+						deliver(&self.streams, (session_id, stream_id), data).await;
+					},
+					_ => deliver(&self.sessions, session_id, data).await
+				}
+			}
+		}
+	}
+
+	// 会话级事件，会话和它的每个流都要知道
+	async fn to_session(&self, session_id: UBig, data: channel::Datagram) {
+		let stream_ids = self.streams
+			.iter()
+			.filter(|entry| entry.key().0 == session_id)
+			.map(|entry| entry.key().1.clone())
+			.collect::<Vec<_>>();
+		for stream_id in stream_ids {
+			deliver(&self.streams, (session_id.clone(), stream_id), data.clone()).await;
+		}
+
+		deliver(&self.sessions, session_id, data).await;
+	}
+}
+
+// 投递给一个所有者，它已经退出就注销
+async fn deliver<K: Eq + std::hash::Hash>(owners: &DashMap<K, flume::Sender<channel::Datagram>>, key: K, data: channel::Datagram) {
+	// 不能跨 await 持有 DashMap 的引用
+	let Some(sender) = owners.get(&key).map(|sender| sender.clone()) else {
+		return;
+	};
+
+	if sender.send_async(data).await.is_err() {
+		owners.remove_if(&key, |_, current| current.same_channel(&sender));
+	}
+}
\ No newline at end of file
@@ -0,0 +1,94 @@
+use tokio_with_wasm::alias::{
+	select,
+	sync::mpsc,
+	time::interval
+};
+
+use super::dispatch::Inbox;
+use super::link::{send_datagram, InnerChannel, InnerContext, LinkMode, Outgoing};
+use super::packet::{channel, Reason};
+use super::session::kill_session;
+
+/// Keep the heartbeat going and flip `DisconnectedStatus` when it stops.
+///
+/// The client sends `Ping` and counts the ones left without `Pong`,
+/// the server counts the intervals passed without `Ping`.
+pub(crate) async fn health_handler(mode: LinkMode, channel: InnerChannel, receiver: Inbox, io_sender: mpsc::UnboundedSender<Outgoing>, context: InnerContext) {
+	use channel::{Datagram, Event as WrapEvent, link::{Event as LinkEvent, Health}, IdSet};
+
+	let mut ticker = interval(context.options.ping_interval);
+	let mut missed = 0u32;
+
+	loop {
+		select! {
+			_ = ticker.tick() => {
+				if missed >= context.options.missed_pongs
+				&& context.disconnected.set(true) {
+					context.runtime.spawn_local(reconnect_watchdog(channel.clone(), context.clone()));
+				}
+
+				// 断开期间也继续发送，才能知道何时恢复
+				if let LinkMode::Client = mode {
+					send_datagram(&io_sender, Datagram {
+						id: IdSet {
+							event: Some(context.event_id.next()),
+							session: None,
+							stream: None
+						},
+						event: WrapEvent::Link(LinkEvent::Health(Health::Ping))
+					});
+				}
+
+				missed = missed.saturating_add(1);
+			},
+			result = receiver.recv_async() => {
+				let Ok(data) = result else {
+					break;
+				};
+
+				// 只有对方发来的心跳才算数
+				let alive = matches!(
+					(&mode, data.event),
+					(LinkMode::Client, WrapEvent::Link(LinkEvent::Health(Health::Pong)))
+					| (LinkMode::Server, WrapEvent::Link(LinkEvent::Health(Health::Ping)))
+				);
+
+				if alive {
+					missed = 0;
+					context.disconnected.set(false);
+				}
+			},
+			// 链接已关闭
+			_ = context.wait_stopped() => break,
+			// 重新连接后不再沿用旧连接上的计数，否则很快又会判定断开
+			_ = context.disconnected.notify.notified() => {
+				if !context.disconnected.is_disconnected() {
+					missed = 0;
+				}
+			}
+		}
+	}
+}
+
+// 断开后处理各个会话的去留
+async fn reconnect_watchdog(channel: InnerChannel, context: InnerContext) {
+	// 结束会话，由 link_handler 和会话自行清理
+	fn kill(channel: &InnerChannel, context: &InnerContext, filter: impl Fn(&channel::session::OpenOptions) -> bool) {
+		let session_ids = context.session_options
+			.iter()
+			.filter(|entry| filter(entry.value()))
+			.map(|entry| entry.key().clone())
+			.collect::<Vec<_>>();
+
+		for session_id in session_ids {
+			kill_session(channel, context, session_id, Reason::TIMEOUT);
+		}
+	}
+
+	// 不允许重连的会话直接结束
+	kill(&channel, &context, |options| !options.allow_reconnect);
+
+	if context.disconnected.wait_reconnect(context.options.reconnect_timeout).await.is_err() {
+		kill(&channel, &context, |_| true);
+	}
+}
\ No newline at end of file
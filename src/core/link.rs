@@ -1,33 +1,123 @@
-use crate::io::LinkIO;
-use std::{sync::{atomic::AtomicBool, Arc}, vec};
+use crate::io::{IOError, LinkIO};
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration, vec};
 use bytes::Bytes;
 use dashmap::DashMap;
+use derive_builder::Builder;
 use forever_safer::atomic_poll::AtomicPoll;
 use tokio_with_wasm::alias::{
 	select,
 	task::LocalSet,
-	sync::{broadcast, mpsc, Notify}
+	sync::{mpsc, oneshot, watch, Notify},
+	time::timeout
 };
 use ibig::UBig;
 use thiserror::Error;
 
-use super::strategy::Strategy;
-use super::packet::{channel, handle_flatbuffer};
+use super::strategy::{LinkInfo, Strategy};
+use super::packet::{channel, handle_flatbuffer, encode_datagram, Reason, ReasonCode};
+use super::session::{close_session, kill_session, Session};
+use super::pending::{PendingRequests, Replies, Reply};
+use super::dispatch::{Dispatcher, Inbox};
+use super::health::health_handler;
+use super::stream::CHUNK_SIZE;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
 pub enum LinkError {
+	#[error("Rejected by the other party: {0}.")]
+	Rejected(Reason),
+	#[error("Timeout while waiting for the other party.")]
+	Timeout,
+	#[error("Connection dropped and could not be resumed.")]
+	Disconnected,
+	#[error("Protocol violated: {0}.")]
+	Protocol(Reason),
+	#[error("This session or stream has already ended.")]
+	Closed,
 
+	#[error(transparent)]
+	Io(#[from] IOError),
 }
 
+impl From<Reason> for LinkError {
+	fn from(reason: Reason) -> Self {
+		match reason.kind() {
+			// 正常结束或对方下线，不是拒绝
+			ReasonCode::Normal | ReasonCode::GoingAway => Self::Closed,
+			ReasonCode::Timeout => Self::Timeout,
+			ReasonCode::Disconnected => Self::Disconnected,
+			ReasonCode::ProtocolError
+			| ReasonCode::IllegalState
+			| ReasonCode::WrongDirection
+			| ReasonCode::UnsupportedVersion => Self::Protocol(reason),
+			_ => Self::Rejected(reason)
+		}
+	}
+}
+
+/// Allocates ids for one side of a link.
+///
+/// The client draws even ids and the server odd ones,
+/// so both sides can start things at the same time without colliding.
+pub struct IdAllocator {
+	poll: AtomicPoll,
+	parity: u8
+}
+
+impl IdAllocator {
+	pub fn new(mode: &LinkMode) -> Self {
+		Self {
+			poll: AtomicPoll::new(),
+			parity: match mode {
+				LinkMode::Client => 0,
+				LinkMode::Server => 1
+			}
+		}
+	}
+
+	pub fn next(&self) -> UBig {
+		self.poll.get_and_increase() * UBig::from(2u8) + UBig::from(self.parity)
+	}
+}
+
+/// Why a session or stream stopped, shared by its handles.
+#[derive(Clone, Default)]
+pub(crate) struct Ended(Arc<Mutex<Option<LinkError>>>);
+
+impl Ended {
+	// 只记录第一次的原因
+	pub fn set(&self, error: LinkError) {
+		if let Ok(mut ended) = self.0.lock()
+		&& ended.is_none() {
+			*ended = Some(error);
+		}
+	}
+
+	pub fn peek(&self) -> Option<LinkError> {
+		self.0.lock().ok().and_then(|ended| ended.clone())
+	}
+
+	// 没有记录的原因就是正常结束
+	pub fn get(&self) -> LinkError {
+		self.peek().unwrap_or(LinkError::Closed)
+	}
+}
+
+// 交给 IO 发送的包，带上事件 ID，发送失败时告知等待回应的请求
+pub(crate) type Outgoing = (Option<UBig>, Bytes);
+
 #[derive(Clone)]
 pub struct InnerChannel {
-	pub receiver_master: broadcast::Sender<channel::Datagram>,
+	pub(crate) dispatcher: Arc<Dispatcher>,
 	pub sender: mpsc::UnboundedSender<channel::Datagram>
 }
 
 impl InnerChannel {
-	pub fn get_receiver(&self) -> broadcast::Receiver<channel::Datagram> {
-		return self.receiver_master.subscribe();
+	pub fn subscribe_session(&self, session_id: &UBig) -> Inbox {
+		return self.dispatcher.session(session_id.clone());
+	}
+
+	pub fn subscribe_stream(&self, session_id: &UBig, stream_id: &UBig) -> Inbox {
+		return self.dispatcher.stream(session_id.clone(), stream_id.clone());
 	}
 
 	pub fn get_sender(&self) -> mpsc::UnboundedSender<channel::Datagram> {
@@ -40,17 +130,193 @@ pub struct DisconnectedStatus {
 	pub status: AtomicBool
 }
 
+impl DisconnectedStatus {
+	pub fn is_disconnected(&self) -> bool {
+		self.status.load(Ordering::SeqCst)
+	}
+
+	// 返回状态是否发生了变化
+	pub(crate) fn set(&self, disconnected: bool) -> bool {
+		let changed = self.status.swap(disconnected, Ordering::SeqCst) != disconnected;
+		if changed {
+			self.notify.notify_waiters();
+		}
+
+		changed
+	}
+
+	// 换上了新连接，心跳重新计数
+	pub(crate) fn revive(&self) {
+		self.status.store(false, Ordering::SeqCst);
+		self.notify.notify_waiters();
+	}
+
+	/// Wait until the link counts as disconnected.
+	pub async fn wait_disconnected(&self) {
+		loop {
+			// 先注册再检查，避免错过通知
+			let notified = self.notify.notified();
+			if self.is_disconnected() {
+				return;
+			}
+
+			notified.await;
+		}
+	}
+
+	/// Wait until the link is back, or fail with `Timeout` after `duration`.
+	pub async fn wait_reconnect(&self, duration: Duration) -> Result<(), LinkError> {
+		timeout(duration, async {
+			loop {
+				let notified = self.notify.notified();
+				if !self.is_disconnected() {
+					return;
+				}
+
+				notified.await;
+			}
+		}).await.map_err(|_| LinkError::Timeout)
+	}
+}
+
+/// Tunables of a link.
+#[derive(Clone, Builder)]
+#[builder(default)]
+pub struct LinkOptions {
+	/// Time between two heartbeats.
+	pub ping_interval: Duration,
+	/// Heartbeats left unanswered before the link counts as disconnected.
+	pub missed_pongs: u32,
+	/// How long sessions wait for the link to come back before dying.
+	pub reconnect_timeout: Duration,
+	/// Unread bytes on a stream above which the sender is asked to wait.
+	pub stream_high_water: usize,
+	/// Unread bytes on a stream below which the sender may continue.
+	pub stream_low_water: usize,
+	/// Out-of-order bytes held back on an ordered stream, chunks beyond it are dropped and sent again later.
+	pub stream_reorder_limit: usize,
+	/// Unacknowledged bytes a stream writer keeps for retransmission before `write` waits for `ChunkAck`.
+	pub stream_send_window: usize,
+	/// Largest amount of data carried by a single chunk, datagram transports keep it under the MTU.
+	pub stream_chunk_size: usize,
+	/// Metadata about the other party handed to the strategy, such as a tenant or an address.
+	pub peer: HashMap<String, String>,
+	/// How long closing a session may wait for its streams before it is ended with `Death`.
+	pub close_timeout: Duration,
+	/// How long a request waits for its Ack.
+	pub request_timeout: Duration,
+	/// How many datagrams each session, stream or link task may have queued before the bus waits for it.
+	pub dispatch_capacity: usize,
+}
+
+impl Default for LinkOptions {
+	fn default() -> Self {
+		Self {
+			ping_interval: Duration::from_secs(5),
+			missed_pongs: 3,
+			reconnect_timeout: Duration::from_secs(30),
+			stream_high_water: 4 * 1024 * 1024,
+			stream_low_water: 1024 * 1024,
+			stream_reorder_limit: 4 * 1024 * 1024,
+			stream_send_window: 8 * 1024 * 1024,
+			stream_chunk_size: CHUNK_SIZE,
+			peer: HashMap::new(),
+			close_timeout: Duration::from_secs(10),
+			request_timeout: Duration::from_secs(30),
+			dispatch_capacity: 256
+		}
+	}
+}
+
+/// Which side of the link emitted an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+	Local,
+	Remote
+}
+
+/// Lifecycle of a session on the link.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+	/// `Open` has been sent by one side, waiting for `OpenAck`.
+	Opening(Side),
+	/// Both sides agreed, the session is usable.
+	Opened,
+	/// `Close` has been sent by one side, waiting for `CloseAck`.
+	Closing(Side),
+	/// `Reopen` has been sent by one side over a new transport, waiting for `ReopenAck`.
+	Reopening(Side),
+}
+
+impl SessionState {
+	/// Check an event against the current state and get the next state.
+	///
+	/// `None` means the session no longer exists.
+	pub fn transition(current: Option<Self>, event: &channel::session::Event, side: Side) -> Result<Option<Self>, Reason> {
+		use channel::session::Event;
+		use super::strategy::Acceptable;
+		use SessionState::*;
+
+		match (current, event) {
+			(None, Event::Open(_)) => Ok(Some(Opening(side))),
+			// Ack 只能由另一方发出
+			(Some(Opening(from)), Event::OpenAck(ack)) if from != side => match ack {
+				Acceptable::Accept => Ok(Some(Opened)),
+				Acceptable::Reject(_) => Ok(None),
+			},
+			(Some(Opened) | Some(Reopening(_)), Event::Reopen) => Ok(Some(Reopening(side))),
+			(Some(Reopening(from)), Event::ReopenAck(ack)) if from != side => match ack {
+				Acceptable::Accept => Ok(Some(Opened)),
+				Acceptable::Reject(_) => Ok(None),
+			},
+			(Some(Opened), Event::Close) => Ok(Some(Closing(side))),
+			(Some(Closing(from)), Event::CloseAck(ack)) if from != side => match ack {
+				Acceptable::Accept => Ok(None),
+				Acceptable::Reject(_) => Ok(Some(Opened)),
+			},
+			// 双方同时关闭，同意对方的 Close，仍等待自己的 CloseAck
+			(Some(Closing(from)), Event::Close) if from != side => Ok(Some(Closing(from))),
+			(Some(_), Event::Death(_)) => Ok(None),
+			_ => Err(Reason::ILLEGAL_STATE)
+		}
+	}
+}
+
 #[derive(Clone)]
 pub struct InnerContext {
 	pub runtime: Arc<LocalSet>,
-	pub disconnected: Arc<DisconnectedStatus>
+	pub disconnected: Arc<DisconnectedStatus>,
+	pub strategy: Arc<dyn Strategy>,
+	pub info: LinkInfo,
+	pub options: LinkOptions,
+	pub sessions: Arc<DashMap<UBig, SessionState>>,
+	pub session_options: Arc<DashMap<UBig, channel::session::OpenOptions>>,
+	// 各会话中尚未完成的流的数量
+	pub session_drains: Arc<DashMap<UBig, watch::Receiver<usize>>>,
+	pub event_id: Arc<IdAllocator>,
+	pub session_id: Arc<IdAllocator>,
+	pub(crate) requests: Arc<PendingRequests>,
+	// 有会话被移除
+	pub(crate) sessions_removed: Arc<Notify>,
+	// 链接已关闭，后台任务随之退出
+	pub(crate) stopped: Arc<watch::Sender<bool>>
+}
+
+impl InnerContext {
+	// 等待关闭链接
+	pub(crate) async fn wait_stopped(&self) {
+		let _ = self.stopped.subscribe().wait_for(|stopped| *stopped).await;
+	}
 }
 
 #[derive(Clone)]
 pub struct Link {
 	mode: LinkMode,
 	channel: InnerChannel,
-	context: InnerContext
+	context: InnerContext,
+	incoming: flume::Receiver<Session>,
+	io_replacer: mpsc::UnboundedSender<Arc<dyn LinkIO>>,
+	io_closer: mpsc::UnboundedSender<oneshot::Sender<()>>
 }
 
 #[derive(Clone)]
@@ -66,13 +332,17 @@ pub enum ModeSession {
 
 impl Link {
 	pub fn new(io: Arc<dyn LinkIO>, mode: LinkMode, strategy: Arc<dyn Strategy>) -> Self {
+		Self::with_options(io, mode, strategy, LinkOptions::default())
+	}
+
+	pub fn with_options(io: Arc<dyn LinkIO>, mode: LinkMode, strategy: Arc<dyn Strategy>, options: LinkOptions) -> Self {
 		let runtime = Arc::new(LocalSet::new());
 
 		// 建立内部数据交换通道
-		let (receiver_master, _) = broadcast::channel::<channel::Datagram>(32);
+		let (dispatcher, link_inbox, health_inbox) = Dispatcher::new(options.dispatch_capacity);
 		let (channel_sender, listener) = mpsc::unbounded_channel::<channel::Datagram>();
 		let channel = InnerChannel {
-			receiver_master,
+			dispatcher: dispatcher.into(),
 			sender: channel_sender
 		};
 
@@ -82,95 +352,332 @@ impl Link {
 			disconnected: DisconnectedStatus {
 				status: AtomicBool::new(false),
 				notify: Notify::new()
-			}.into()
+			}.into(),
+			strategy,
+			info: LinkInfo {
+				mode: mode.clone(),
+				peer: options.peer.clone().into()
+			},
+			options,
+			sessions: DashMap::new().into(),
+			session_options: DashMap::new().into(),
+			session_drains: DashMap::new().into(),
+			event_id: IdAllocator::new(&mode).into(),
+			session_id: IdAllocator::new(&mode).into(),
+			requests: Arc::new(PendingRequests::default()),
+			sessions_removed: Arc::new(Notify::new()),
+			stopped: Arc::new(watch::channel(false).0)
 		};
 
+		// 已被策略接受的会话
+		let (incoming_sender, incoming) = flume::unbounded::<Session>();
+
 		// 建立 IO 二进制数据交换通道
-		let (io_sender, io_receiver) = mpsc::unbounded_channel::<Bytes>();
+		let (io_sender, io_receiver) = mpsc::unbounded_channel::<Outgoing>();
+		// 更换底层连接
+		let (io_replacer, io_replaced) = mpsc::unbounded_channel::<Arc<dyn LinkIO>>();
+		// 关闭底层连接
+		let (io_closer, io_closed) = mpsc::unbounded_channel::<oneshot::Sender<()>>();
 		
+		// 分发内部数据
+		runtime.spawn_local(bus_handler(listener, channel.clone(), context.clone()));
 		// 处理解析 IO 数据
-		runtime.spawn_local(io_handler(io, channel.clone(), io_receiver, context.clone()));
+		runtime.spawn_local(io_handler(io, channel.clone(), io_receiver, io_replaced, io_closed, context.clone()));
+		// 处理心跳
+		runtime.spawn_local(health_handler(mode.clone(), channel.clone(), health_inbox, io_sender.clone(), context.clone()));
 		// 处理 Link 数据
-		runtime.spawn_local(link_handler(channel.clone(), io_sender, context.clone()));
+		runtime.spawn_local(link_handler(channel.clone(), link_inbox, io_sender, context.clone(), incoming_sender));
 
 		Self {
 			mode,
 			channel,
-			context
+			context,
+			incoming,
+			io_replacer,
+			io_closer
 		}
 	}
 
-	pub async fn wait_session(&self) {
-		
+	pub fn mode(&self) -> LinkMode {
+		self.mode.clone()
+	}
+
+	/// Whether the heartbeat has stopped getting answers.
+	pub fn is_disconnected(&self) -> bool {
+		self.context.disconnected.is_disconnected()
+	}
+
+	/// Drive the background tasks of the link while `future` runs.
+	pub async fn run_until<F: Future>(&self, future: F) -> F::Output {
+		self.context.runtime.run_until(future).await
+	}
+
+	/// Wait for the next session opened by the other party and accepted by the `Strategy`.
+	pub async fn wait_session(&self) -> Result<Session, LinkError> {
+		self.incoming.recv_async().await.map_err(|_| LinkError::Closed)
 	}
 
-	pub async fn create_session(&self, options: channel::session::OpenOptions) {
-		use super::packet::get_event_id;
+	/// Request the other party to open a new session.
+	///
+	/// Fails with `Rejected` if the other party refused it.
+	pub async fn create_session(&self, options: channel::session::OpenOptions) -> Result<Session, LinkError> {
 		use super::strategy::Acceptable;
 		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, session::Event, IdSet};
 
+		if self.is_disconnected() {
+			return Err(LinkError::Disconnected);
+		}
+
 		let channel = self.channel.clone();
-		let sender = channel.get_sender();
-		let event_id = get_event_id();
+		let event_id = self.context.event_id.next();
+		let session_id = self.context.session_id.next();
 		let data = Datagram {
 			id: IdSet {
 				event: Some(event_id.clone()),
-				session: None,
+				session: Some(session_id.clone()),
 				stream: None,
 			},
-			event: WrapEvent::Link(LinkEvent::SessionAck(Event::Open(options)))
+			event: WrapEvent::Link(LinkEvent::SessionAck(Event::Open(options.clone())))
 		};
-		sender.send(data).unwrap();
 
-		let mut receiver = channel.get_receiver();
-		let ack: Acceptable;
-		loop {
-			match receiver.recv().await {
-				Ok(value) if value.id.event == Some(event_id.clone()) => match value.event {
-					WrapEvent::Session(Event::OpenAck(the_ack)) => {
-						ack = the_ack;
-						break;
-					},
-					_ => continue
-				},
-				_ => continue
-			}
-		}
+		// 先订阅和登记，避免错过回应和后续的包
+		let receiver = channel.subscribe_session(&session_id);
+		let mut response = self.context.requests.register(event_id);
+
+		let ack = response.resend(&channel.get_sender(), data, self.context.options.request_timeout, |data| match data.event {
+			WrapEvent::Session(Event::OpenAck(ack)) if data.id.session.as_ref() == Some(&session_id) => Some(ack),
+			_ => None
+		}).await?;
 
 		if let Acceptable::Reject(reason) = ack {
-			return;
+			return Err(reason.into());
 		}
 
-		match self.mode {
-			LinkMode::Server => todo!(),
-			LinkMode::Client => todo!(),
+		Ok(Session::new(session_id, options, Side::Local, channel, self.context.clone(), receiver))
+	}
+
+	/// Move the link onto a fresh transport.
+	///
+	/// The client then reopens every live session that allows reconnecting,
+	/// and the data not yet acknowledged is sent again once the other party agrees.
+	/// The server only swaps the transport and waits for the client to reopen.
+	/// Returns the ids of the sessions that were resumed.
+	pub async fn reattach(&self, io: Arc<dyn LinkIO>) -> Result<Vec<UBig>, LinkError> {
+		use super::strategy::Acceptable;
+		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, session::Event, IdSet};
+
+		self.io_replacer.send(io).map_err(|_| LinkError::Closed)?;
+		self.context.disconnected.revive();
+
+		// 重连由客户端发起
+		if let LinkMode::Server = self.mode {
+			return Ok(vec![]);
 		}
+
+		let session_ids = self.context.session_options
+			.iter()
+			.filter(|entry| entry.value().allow_reconnect)
+			.filter(|entry| self.context.sessions.get(entry.key()).is_some_and(|state| *state == SessionState::Opened))
+			.map(|entry| entry.key().clone())
+			.collect::<Vec<_>>();
+
+		let reopens = session_ids.into_iter().map(|session_id| async move {
+			let event_id = self.context.event_id.next();
+			let data = Datagram {
+				id: IdSet {
+					event: Some(event_id.clone()),
+					session: Some(session_id.clone()),
+					stream: None
+				},
+				event: WrapEvent::Link(LinkEvent::SessionAck(Event::Reopen))
+			};
+
+			let mut response = self.context.requests.register(event_id);
+
+			let ack = response.resend(&self.channel.get_sender(), data, self.context.options.request_timeout, |data| match data.event {
+				WrapEvent::Session(Event::ReopenAck(ack)) if data.id.session.as_ref() == Some(&session_id) => Some(ack),
+				_ => None
+			}).await.ok()?;
+
+			match ack {
+				Acceptable::Accept => Some(session_id),
+				Acceptable::Reject(_) => None
+			}
+		});
+
+		Ok(futures::future::join_all(reopens)
+			.await
+			.into_iter()
+			.flatten()
+			.collect())
+	}
+
+	/// Close every session like `Session::close`, then close the transport.
+	///
+	/// Sessions that are not open, or refuse to close, are ended with `Death`.
+	pub async fn shutdown(&self) -> Result<(), LinkError> {
+		let session_ids = self.context.sessions
+			.iter()
+			.map(|entry| (entry.key().clone(), *entry.value()))
+			.collect::<Vec<_>>();
+
+		let closes = session_ids.into_iter().map(|(session_id, state)| async move {
+			let closed = match state {
+				SessionState::Opened => close_session(&self.channel, &self.context, &session_id).await.is_ok(),
+				_ => false
+			};
+
+			if !closed {
+				kill_session(&self.channel, &self.context, session_id, Reason::GOING_AWAY);
+			}
+		});
+		futures::future::join_all(closes).await;
+
+		// 会话都移除后，告别的包已经交给 IO
+		let _ = timeout(self.context.options.close_timeout, async {
+			loop {
+				// 先注册再检查，避免错过通知
+				let removed = self.context.sessions_removed.notified();
+				if self.context.sessions.is_empty() {
+					return;
+				}
+
+				removed.await;
+			}
+		}).await;
+
+		let (done_sender, done) = oneshot::channel();
+		let closed = match self.io_closer.send(done_sender) {
+			Ok(_) => done.await.map_err(|_| LinkError::Closed),
+			Err(_) => Err(LinkError::Closed)
+		};
+
+		// 其余后台任务随之退出，不再接受新的会话
+		self.context.stopped.send_replace(true);
+		closed
+	}
+}
+
+/// Whether `data` ends the session `session_id`, sent by either side, and why.
+///
+/// A normal close ends it with `Closed`.
+pub(crate) fn ends_session(data: &channel::Datagram, session_id: &UBig) -> Option<LinkError> {
+	use channel::{Event as WrapEvent, link::Event as LinkEvent, session::Event};
+	use super::strategy::Acceptable;
+
+	if data.id.session.as_ref() != Some(session_id) {
+		return None;
+	}
+
+	let event = match &data.event {
+		WrapEvent::Session(event) => event,
+		WrapEvent::Link(LinkEvent::SessionAck(event)) => event,
+		_ => return None
+	};
+
+	match event {
+		Event::Death(reason) => Some(reason.clone().into()),
+		Event::CloseAck(Acceptable::Accept) => Some(LinkError::Closed),
+		Event::ReopenAck(Acceptable::Reject(_)) => Some(LinkError::Disconnected),
+		_ => None
 	}
 }
 
-async fn io_handler(io: Arc<dyn LinkIO>, channel: InnerChannel, mut io_receiver: mpsc::UnboundedReceiver<Bytes>, context: InnerContext) {
+async fn io_handler(
+	mut io: Arc<dyn LinkIO>,
+	channel: InnerChannel,
+	mut io_receiver: mpsc::UnboundedReceiver<Outgoing>,
+	mut io_replaced: mpsc::UnboundedReceiver<Arc<dyn LinkIO>>,
+	mut io_closed: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+	context: InnerContext
+) {
 	use crate::io::{WritterStream, ReaderStream};
 	let writter_streams = DashMap::new();
 	let writter_stream_id = AtomicPoll::new();
 
+	// 先尝试从已有发送流中发送，不行再自己创建流
+	async fn write_buffer(
+		io: &Arc<dyn LinkIO>,
+		writter_streams: &DashMap<UBig, Arc<dyn WritterStream>>,
+		writter_stream_id: &AtomicPoll,
+		buffer: Bytes
+	) -> Result<(), crate::io::IOError> {
+		let ubytes = buffer.to_vec();
+		let mut removed = vec![];
+		let mut sent = false;
+
+		for (id, writter) in writter_streams.clone().into_iter() {
+			match writter.write(&ubytes).await {
+				Ok(()) => {
+					// 那就是发送了呗
+					sent = true;
+					break;
+				},
+				Err(crate::io::IOError::ClosedStream) => {
+					// 关闭就要删除
+					removed.push(id);
+				},
+				Err(_) => {},
+			}
+		}
+
+		// 顺手的事
+		for id in &removed {
+			writter_stream_id.release(id.clone());
+			writter_streams.remove(id);
+		}
+		drop(removed);
+
+		if sent {
+			return Ok(());
+		}
+
+		// 到这里说明需要自己创建流，失败就放弃发送
+		let stream = io.open_uni_stream().await?;
+		stream.write(&ubytes).await?;
+
+		// 留着下次用
+		writter_streams.insert(writter_stream_id.get_and_increase(), stream);
+		Ok(())
+	}
+
+	// 发送失败时，等待回应的请求不必再等
+	async fn write_outgoing(
+		io: &Arc<dyn LinkIO>,
+		writter_streams: &DashMap<UBig, Arc<dyn WritterStream>>,
+		writter_stream_id: &AtomicPoll,
+		context: &InnerContext,
+		(event_id, buffer): Outgoing
+	) {
+		if let Err(error) = write_buffer(io, writter_streams, writter_stream_id, buffer).await
+		&& let Some(event_id) = event_id {
+			context.requests.fail(&event_id, LinkError::Io(error));
+		}
+	}
+
 	// 处理 Reader
 	async fn wrap_reader(reader: Arc<dyn ReaderStream>, channel: InnerChannel) {
 		loop {
 			let buffer = match reader.read().await {
 				Ok(buffer) => Bytes::copy_from_slice(&buffer),
+				// 流已经关闭，不会再有数据
+				Err(crate::io::IOError::ClosedStream | crate::io::IOError::Disconnected) => break,
+				// 超时可以再试
+				Err(crate::io::IOError::OpenTimeout | crate::io::IOError::AcceptTimeout) => continue,
+				// 其他错误多半会一直出现，比如帧过大，不再读这个流
 				Err(_) => {
-					continue;
+					let _ = reader.close().await;
+					break;
 				}
 			};
 
 			use crate::protocol::packet::{Packet, MutPacket};
 			let mut packets = vec![];
 
-			if let Ok(mut_root) = flatbuffers::root::<MutPacket>(&buffer) {
+			if let Ok(mut_root) = flatbuffers::root::<MutPacket>(&buffer)
+			&& let Some(roots) = mut_root.batch() {
 				// 多包粘合 Batch
-				if let Some(roots) = mut_root.batch() {
-					packets.append(&mut roots.iter().collect::<Vec<_>>());
-				}
+				packets.append(&mut roots.iter().collect::<Vec<_>>());
 			} else if let Ok(root) = flatbuffers::root::<Packet>(&buffer) {
 				// 单个包
 				packets.push(root);
@@ -194,97 +701,829 @@ async fn io_handler(io: Arc<dyn LinkIO>, channel: InnerChannel, mut io_receiver:
 			}
 		}
 	}
+
+	// 立即出错的 accept 不再轮询，否则会空转占满 LocalSet，直到换上新连接
+	let mut bi_dead = false;
+	let mut uni_dead = false;
+	let mut replaceable = true;
 	
 	loop {
 		select! {
-			try_bi_stream = io.accept_bi_stream() => {
-				if let Ok(stream) = try_bi_stream {
-					// 干湿分离
-					let writter = stream.clone() as Arc<dyn WritterStream>;
-					writter_streams.insert(writter_stream_id.get_and_increase(), writter);
-
-					let reader = stream.clone() as Arc<dyn ReaderStream>;
-					context.runtime.spawn_local(wrap_reader(reader, (&channel).clone()));
+			try_bi_stream = io.accept_bi_stream(), if !bi_dead => {
+				match try_bi_stream {
+					Ok(stream) => {
+						// 干湿分离
+						let writter = stream.clone() as Arc<dyn WritterStream>;
+						writter_streams.insert(writter_stream_id.get_and_increase(), writter);
+
+						let reader = stream.clone() as Arc<dyn ReaderStream>;
+						context.runtime.spawn_local(wrap_reader(reader, channel.clone()));
+					},
+					// 连接断了，两种流都不会再来
+					Err(crate::io::IOError::Disconnected) => {
+						bi_dead = true;
+						uni_dead = true;
+					},
+					Err(_) => bi_dead = true
 				}
 			},
-			try_uni_stream = io.accept_uni_stream() => {
-				if let Ok(reader) = try_uni_stream {
-					context.runtime.spawn_local(wrap_reader(reader, (&channel).clone()));
+			try_uni_stream = io.accept_uni_stream(), if !uni_dead => {
+				match try_uni_stream {
+					Ok(reader) => {
+						context.runtime.spawn_local(wrap_reader(reader, channel.clone()));
+					},
+					Err(crate::io::IOError::Disconnected) => {
+						bi_dead = true;
+						uni_dead = true;
+					},
+					Err(_) => uni_dead = true
 				}
 			},
-			try_buffer = io_receiver.recv() => {
-				if let Some(buffer) = try_buffer {
-					let ubytes = buffer.to_vec();
-					let mut removed = vec![];
-
-					// 先尝试从已有发送流中发送
-					for (id, writter) in writter_streams.clone().into_iter() {
-						match writter.write(&ubytes).await {
-							Ok(()) => {
-								// 那就是发送了呗
-								continue;
-							},
-							Err(crate::io::IOError::ClosedStream) => {
-								// 关闭就要删除
-								removed.push(id);
-							},
-							Err(_) => {},
+			try_io = io_replaced.recv(), if replaceable => {
+				match try_io {
+					Some(new_io) => {
+						// 旧连接的发送流不再使用
+						for entry in writter_streams.iter() {
+							writter_stream_id.release(entry.key().clone());
 						}
-					}
+						writter_streams.clear();
 
-					// 顺手的事
-					for id in &removed {
-						writter_stream_id.release(id.clone());
-						writter_streams.remove(id);
-					}
-					drop(removed);
+						io = new_io;
+						bi_dead = false;
+						uni_dead = false;
+					},
+					// Link 都不在了，不会再有新连接
+					None => replaceable = false
+				}
+			},
+			try_close = io_closed.recv() => {
+				// 先把排队的包发完
+				while let Ok(outgoing) = io_receiver.try_recv() {
+					write_outgoing(&io, &writter_streams, &writter_stream_id, &context, outgoing).await;
+				}
 
-					// 到这里说明需要自己创建流
-					let stream = match io.open_uni_stream().await {
-						Ok(writter) => writter,
-						Err(_) => {
-							// 失败就放弃发送
-							continue;
-						},
-					};
-					
-					match stream.write(&ubytes).await {
-						Ok(_) => {},
-						Err(_) => {}
-					}
+				let writters = writter_streams.iter().map(|entry| entry.value().clone()).collect::<Vec<_>>();
+				for writter in writters {
+					let _ = writter.close().await;
+				}
+				writter_streams.clear();
+
+				if let Some(done) = try_close {
+					let _ = done.send(());
+				}
+				break;
+			},
+			try_outgoing = io_receiver.recv() => {
+				if let Some(outgoing) = try_outgoing {
+					write_outgoing(&io, &writter_streams, &writter_stream_id, &context, outgoing).await;
 				}
 			}
 		}
 	}
 }
 
-async fn link_handler(channel: InnerChannel, io_sender: mpsc::UnboundedSender<Bytes>, context: InnerContext) {
-	let mut receiver = channel.get_receiver();
+// 将内部数据分发给它的所有者，回应先交给等待它的请求
+async fn bus_handler(mut listener: mpsc::UnboundedReceiver<channel::Datagram>, channel: InnerChannel, context: InnerContext) {
 	loop {
-		let data = match receiver.recv().await {
-			Ok(value) => value,
-			Err(error) => {
-				continue;
-			}
+		let data = select! {
+			data = listener.recv() => data,
+			_ = context.wait_stopped() => None
+		};
+		let Some(data) = data else {
+			break;
 		};
 
-		let event = match data.event {
-			channel::Event::Link(event) => event,
-			// 其他不关我们的事
-			_ => {
-				continue;
+		context.requests.resolve(&data);
+		channel.dispatcher.dispatch(data).await;
+	}
+}
+
+// 序列化后交给 IO 发送
+pub(crate) fn send_datagram(io_sender: &mpsc::UnboundedSender<Outgoing>, data: channel::Datagram) {
+	let event_id = data.id.event.clone();
+	let _ = io_sender.send((event_id, encode_datagram(data)));
+}
+
+async fn link_handler(channel: InnerChannel, receiver: Inbox, io_sender: mpsc::UnboundedSender<Outgoing>, context: InnerContext, incoming: flume::Sender<Session>) {
+	use channel::{Datagram, Event as WrapEvent, link::{Event as LinkEvent, Health}, session::Event as SessionEvent};
+
+	// 更新会话状态
+	fn apply(channel: &InnerChannel, context: &InnerContext, session_id: UBig, event: &SessionEvent, state: Option<SessionState>) {
+		match state {
+			Some(state) => {
+				if let SessionEvent::Open(options) = event {
+					context.session_options.insert(session_id.clone(), options.clone());
+				}
+				context.sessions.insert(session_id, state);
+			},
+			None => {
+				context.sessions.remove(&session_id);
+				context.session_options.remove(&session_id);
+				context.session_drains.remove(&session_id);
+				channel.dispatcher.forget_session(&session_id);
+				context.sessions_removed.notify_waiters();
 			}
-		};
+		}
+	}
+
+	// 是否是等待回应的请求
+	fn is_request(event: &SessionEvent) -> bool {
+		matches!(event, SessionEvent::Open(_) | SessionEvent::Reopen | SessionEvent::Close)
+	}
 
+	// 对方的请求，重复的直接回应上一次的结果
+	let replies = Arc::new(Replies::default());
+	// 各会话中本地正在等待回应的请求，重发时不再经过状态机
+	let mut requesting = HashMap::<UBig, UBig>::new();
+
+	loop {
+		let data = select! {
+			data = receiver.recv_async() => data,
+			_ = context.wait_stopped() => break
+		};
+		let Ok(data) = data else {
+			break;
+		};
 		let id = data.id;
 
-		use channel::link::Event::*;
-		match event {
-			SessionAck(event) => todo!(),
+		match data.event {
+			// 本地发出的会话事件
+			WrapEvent::Link(LinkEvent::SessionAck(event)) => {
+				let Some(session_id) = id.session.clone() else {
+					continue;
+				};
+
+				let resent = id.event.is_some() && requesting.get(&session_id) == id.event.as_ref();
+				let current = context.sessions.get(&session_id).map(|state| *state);
+				match SessionState::transition(current, &event, Side::Local) {
+					Ok(state) => {
+						match (is_request(&event), &state, &id.event) {
+							(true, Some(_), Some(event_id)) => {
+								requesting.insert(session_id.clone(), event_id.clone());
+							},
+							(_, None, _) => {
+								requesting.remove(&session_id);
+							},
+							_ => {}
+						}
+						apply(&channel, &context, session_id, &event, state);
+					},
+					// 重发的请求照常发出
+					Err(_) if resent => {},
+					// 本地的非法操作不发出去
+					Err(_) => continue
+				}
 
-			StreamAck(event) => todo!(),
+				send_datagram(&io_sender, Datagram {
+					id,
+					event: WrapEvent::Link(LinkEvent::SessionAck(event))
+				});
+			},
 
-			Health(health) => todo!(),
+			// 本地发出的流事件
+			WrapEvent::Link(LinkEvent::StreamAck(event)) => {
+				send_datagram(&io_sender, Datagram {
+					id,
+					event: WrapEvent::Link(LinkEvent::StreamAck(event))
+				});
+			},
+
+			// 对方的心跳
+			WrapEvent::Link(LinkEvent::Health(health)) => match health {
+				Health::Ping => send_datagram(&io_sender, Datagram {
+					id,
+					event: WrapEvent::Link(LinkEvent::Health(Health::Pong))
+				}),
+				Health::Pong => {}
+			},
+
+			// 对方发来的会话事件
+			WrapEvent::Session(event) => {
+				let Some(session_id) = id.session.clone() else {
+					continue;
+				};
+
+				// 对方没收到回应而重发的请求
+				if is_request(&event)
+				&& let Some(event_id) = &id.event {
+					match replies.check(event_id) {
+						Reply::Fresh => {},
+						Reply::Pending => continue,
+						Reply::Answered(answer) => {
+							send_datagram(&io_sender, answer);
+							continue;
+						}
+					}
+				}
+
+				let current = context.sessions.get(&session_id).map(|state| *state);
+				let state = match SessionState::transition(current, &event, Side::Remote) {
+					Ok(state) => state,
+					Err(reason) => {
+						// 对 Death 不再回应，避免互相处决；
+						// 对不上的 Ack 多半是重发的请求得到的第二份回应
+						if let SessionEvent::Death(_) | SessionEvent::OpenAck(_) | SessionEvent::ReopenAck(_) | SessionEvent::CloseAck(_) = event {
+							continue;
+						}
+
+						apply(&channel, &context, session_id.clone(), &event, None);
+						requesting.remove(&session_id);
+
+						let death = Datagram {
+							id,
+							event: WrapEvent::Link(LinkEvent::SessionAck(SessionEvent::Death(reason)))
+						};
+						replies.answer(&death);
+						send_datagram(&io_sender, death);
+						continue;
+					}
+				};
+				// 得到回应或会话结束，本地的请求也就结束了
+				if state.is_none() || !is_request(&event) {
+					requesting.remove(&session_id);
+				}
+				apply(&channel, &context, session_id.clone(), &event, state);
+
+				match event {
+					// 交给策略决定是否接受
+					SessionEvent::Open(options) => {
+						let strategy = context.strategy.clone();
+						let channel = channel.clone();
+						let the_context = context.clone();
+						let incoming = incoming.clone();
+						let replies = replies.clone();
+						context.runtime.spawn_local(async move {
+							let ack = strategy.ack_session_open(&the_context.info, &session_id, &options).await;
+
+							// 先建立会话再回应，避免错过对方后续的包
+							if let super::strategy::Acceptable::Accept = ack {
+								let receiver = channel.subscribe_session(&session_id);
+								let session = Session::new(session_id, options, Side::Remote, channel.clone(), the_context, receiver);
+								let _ = incoming.send(session);
+							}
+
+							let answer = Datagram {
+								id,
+								event: WrapEvent::Link(LinkEvent::SessionAck(SessionEvent::OpenAck(ack)))
+							};
+							replies.answer(&answer);
+							let _ = channel.get_sender().send(answer);
+						});
+					},
+					// 对方通过新连接要求恢复
+					SessionEvent::Reopen => {
+						let options = context.session_options
+							.get(&session_id)
+							.map(|options| options.clone())
+							.filter(|options| options.allow_reconnect);
+
+						let strategy = context.strategy.clone();
+						let channel = channel.clone();
+						let the_context = context.clone();
+						let replies = replies.clone();
+						context.runtime.spawn_local(async move {
+							// 不允许重连的会话不必询问策略
+							let ack = match options {
+								Some(options) => strategy.ack_session_reopen(&the_context.info, &session_id, &options).await,
+								None => super::strategy::Acceptable::Reject(Reason::ILLEGAL_STATE)
+							};
+
+							let answer = Datagram {
+								id,
+								event: WrapEvent::Link(LinkEvent::SessionAck(SessionEvent::ReopenAck(ack)))
+							};
+							replies.answer(&answer);
+							let _ = channel.get_sender().send(answer);
+						});
+					},
+					// 双方同时关闭，不必询问策略；直接发出，自己的 CloseAck 可能先到而移除会话
+					SessionEvent::Close if current == Some(SessionState::Closing(Side::Local)) => {
+						let answer = Datagram {
+							id,
+							event: WrapEvent::Link(LinkEvent::SessionAck(SessionEvent::CloseAck(super::strategy::Acceptable::Accept)))
+						};
+						replies.answer(&answer);
+						send_datagram(&io_sender, answer);
+					},
+					// 对方要求关闭
+					SessionEvent::Close => {
+						let strategy = context.strategy.clone();
+						let channel = channel.clone();
+						let the_context = context.clone();
+						let replies = replies.clone();
+						context.runtime.spawn_local(async move {
+							let ack = strategy.ack_session_close(&the_context.info, &session_id).await;
+
+							let answer = Datagram {
+								id,
+								event: WrapEvent::Link(LinkEvent::SessionAck(SessionEvent::CloseAck(ack)))
+							};
+							replies.answer(&answer);
+							let _ = channel.get_sender().send(answer);
+						});
+					},
+					_ => {}
+				}
+			},
+
+			// 其他不关我们的事
+			WrapEvent::Stream(_) => continue
 		}
 	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use bytes::Bytes;
+	use ibig::UBig;
+
+	use crate::io::memory;
+	use crate::core::packet::channel::{session, stream};
+	use crate::core::strategy::{Acceptable, LinkInfo, Strategy};
+	use super::*;
+
+	struct AcceptAll;
+
+	#[async_trait::async_trait]
+	impl Strategy for AcceptAll {
+		async fn ack_session_open(&self, _link: &LinkInfo, _session_id: &UBig, _options: &session::OpenOptions) -> Acceptable {
+			Acceptable::Accept
+		}
+
+		async fn ack_stream_open(
+			&self,
+			_link: &LinkInfo,
+			_session_id: &UBig,
+			_stream_id: &UBig,
+			_options: &stream::OpenOptions,
+			_length: Option<&UBig>
+		) -> Acceptable {
+			Acceptable::Accept
+		}
+	}
+
+	fn options() -> LinkOptionsBuilder {
+		let mut options = LinkOptionsBuilder::default();
+		options.request_timeout(Duration::from_secs(5)).close_timeout(Duration::from_secs(5));
+		options
+	}
+
+	fn links() -> (Link, Link) {
+		let (left, right) = memory::pair();
+		links_on(Arc::new(left), Arc::new(right), options().build().unwrap())
+	}
+
+	fn links_on(left: Arc<memory::MemoryIO>, right: Arc<memory::MemoryIO>, options: LinkOptions) -> (Link, Link) {
+		(
+			Link::with_options(left, LinkMode::Client, Arc::new(AcceptAll), options.clone()),
+			Link::with_options(right, LinkMode::Server, Arc::new(AcceptAll), options)
+		)
+	}
+
+	// 客户端发起一个会话，服务端接受
+	async fn sessions(client: &Link, server: &Link) -> (Session, Session) {
+		let (opened, accepted) = tokio::join!(
+			client.create_session(session::OpenOptionsBuilder::default().build().unwrap()),
+			server.wait_session()
+		);
+		(opened.unwrap(), accepted.unwrap())
+	}
+
+	#[test]
+	fn reason_to_error() {
+		assert_eq!(LinkError::from(Reason::NORMAL), LinkError::Closed);
+		assert_eq!(LinkError::from(Reason::GOING_AWAY.with_message("shutdown")), LinkError::Closed);
+		assert_eq!(LinkError::from(Reason::TIMEOUT), LinkError::Timeout);
+		assert_eq!(LinkError::from(Reason::DISCONNECTED), LinkError::Disconnected);
+		assert_eq!(LinkError::from(Reason::WRONG_DIRECTION), LinkError::Protocol(Reason::WRONG_DIRECTION));
+		assert_eq!(LinkError::from(Reason::REJECTED), LinkError::Rejected(Reason::REJECTED));
+		assert_eq!(LinkError::from(Reason::application(7).unwrap()), LinkError::Rejected(Reason::application(7).unwrap()));
+	}
+
+	#[tokio::test]
+	async fn round_trip() {
+		let (client, server) = links();
+
+		// 两端各自的 LocalSet 都要驱动
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = tokio::join!(
+				client.create_session(session::OpenOptionsBuilder::default().build().unwrap()),
+				server.wait_session()
+			);
+			let (opened, accepted) = (opened.unwrap(), accepted.unwrap());
+			assert_eq!(opened.id(), accepted.id());
+
+			// 整包
+			let (sent, received) = tokio::join!(
+				opened.send_block(Bytes::from_static(b"block"), true),
+				accepted.recv_block()
+			);
+			sent.unwrap();
+			assert_eq!(received.unwrap(), Bytes::from_static(b"block"));
+
+			// 已知长度的流，方向与会话发起方相反
+			let data = Bytes::from(vec![42u8; 200 * 1024]);
+			let writer = accepted.open_buffer(stream::OpenOptionsBuilder::default().build().unwrap(), data.len() as u64).await.unwrap();
+			let reader = opened.accept_stream().await.unwrap();
+			let (written, read) = tokio::join!(
+				async {
+					writer.write(data.clone()).await?;
+					writer.flush().await
+				},
+				reader.read_to_end()
+			);
+			written.unwrap();
+			assert_eq!(read.unwrap(), data);
+			assert_eq!(writer.progress().borrow().done, data.len() as u64);
+
+			// 关闭后双方都知道会话结束了
+			opened.close().await.unwrap();
+			assert_eq!(accepted.recv_block().await, Err(LinkError::Closed));
+			assert!(client.context.sessions.is_empty());
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn duplicate_open() {
+		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, session::Event, IdSet};
+
+		let (client, server) = links();
+
+		client.run_until(server.run_until(async {
+			// 同一个 Open 发两次，就像第一次的 OpenAck 丢了
+			let event_id = client.context.event_id.next();
+			let session_id = client.context.session_id.next();
+			let data = Datagram {
+				id: IdSet {
+					event: Some(event_id.clone()),
+					session: Some(session_id.clone()),
+					stream: None
+				},
+				event: WrapEvent::Link(LinkEvent::SessionAck(Event::Open(session::OpenOptionsBuilder::default().build().unwrap())))
+			};
+
+			let mut response = client.context.requests.register(event_id);
+			let ack = response.resend(&client.channel.get_sender(), data.clone(), Duration::from_secs(1), |data| match data.event {
+				WrapEvent::Session(Event::OpenAck(Acceptable::Accept)) => Some(()),
+				_ => None
+			}).await;
+			assert_eq!(ack, Ok(()));
+
+			// 线路上多出一份 Open，对方再回应一次 OpenAck
+			let WrapEvent::Link(LinkEvent::SessionAck(open)) = data.event else {
+				unreachable!()
+			};
+			server.channel.get_sender().send(Datagram {
+				id: data.id,
+				event: WrapEvent::Session(open)
+			}).unwrap();
+
+			// 只建立了一个会话，双方也没有因此处决它
+			let accepted = server.wait_session().await.unwrap();
+			assert_eq!(accepted.id(), session_id);
+			assert!(tokio::time::timeout(Duration::from_millis(100), server.wait_session()).await.is_err());
+			assert_eq!(server.context.sessions.get(&session_id).map(|state| *state), Some(SessionState::Opened));
+			assert_eq!(client.context.sessions.get(&session_id).map(|state| *state), Some(SessionState::Opened));
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn crossed_close() {
+		let (client, server) = links();
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = tokio::join!(
+				client.create_session(session::OpenOptionsBuilder::default().build().unwrap()),
+				server.wait_session()
+			);
+			let (opened, accepted) = (opened.unwrap(), accepted.unwrap());
+
+			// 双方同时关闭，都应正常结束而不是互相处决
+			let (left, right) = tokio::join!(opened.close(), accepted.close());
+			assert_eq!(left, Ok(()));
+			assert_eq!(right, Ok(()));
+			assert!(client.context.sessions.is_empty());
+			assert!(server.context.sessions.is_empty());
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn shutdown() {
+		let (client, server) = links();
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = tokio::join!(
+				client.create_session(session::OpenOptionsBuilder::default().build().unwrap()),
+				server.wait_session()
+			);
+			let (opened, accepted) = (opened.unwrap(), accepted.unwrap());
+
+			client.shutdown().await.unwrap();
+			assert_eq!(opened.recv_block().await, Err(LinkError::Closed));
+			assert_eq!(accepted.recv_block().await, Err(LinkError::Closed));
+
+			// 关闭后不再有新的会话
+			assert_eq!(client.wait_session().await.err(), Some(LinkError::Closed));
+			assert!(client.create_session(session::OpenOptionsBuilder::default().build().unwrap()).await.is_err());
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn io_error() {
+		let (left, right) = memory::pair();
+		let left = Arc::new(left);
+		let client = Link::new(left.clone(), LinkMode::Client, Arc::new(AcceptAll));
+		let _server = Link::new(Arc::new(right), LinkMode::Server, Arc::new(AcceptAll));
+
+		// 心跳还没发现断开，请求因发送失败而立即结束
+		left.disconnect();
+		client.run_until(async {
+			let result = client.create_session(session::OpenOptionsBuilder::default().build().unwrap()).await;
+			assert_eq!(result.err(), Some(LinkError::Io(IOError::Disconnected)));
+		}).await;
+	}
+
+	#[tokio::test]
+	async fn wrong_direction() {
+		let (client, server) = links();
+
+		client.run_until(server.run_until(async {
+			let options = session::OpenOptionsBuilder::default().way(session::Ways::OnlyWrite).build().unwrap();
+			let (opened, accepted) = tokio::join!(client.create_session(options), server.wait_session());
+			let (opened, accepted) = (opened.unwrap(), accepted.unwrap());
+
+			// 只写的一方不能读，只读的一方不能写
+			let wrong = LinkError::Protocol(Reason::WRONG_DIRECTION);
+			assert_eq!(opened.recv_block().await, Err(wrong.clone()));
+			assert_eq!(opened.accept_stream().await.err(), Some(wrong.clone()));
+			assert_eq!(accepted.send_block(Bytes::from_static(b"block"), true).await, Err(wrong));
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn reattach() {
+		let (left, right) = memory::pair();
+		let old = Arc::new(left);
+		let (client, server) = links_on(old.clone(), Arc::new(right), options().build().unwrap());
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = tokio::join!(
+				client.create_session(session::OpenOptionsBuilder::default().build().unwrap()),
+				server.wait_session()
+			);
+			let (opened, accepted) = (opened.unwrap(), accepted.unwrap());
+
+			let writer = opened.open_stream(stream::OpenOptionsBuilder::default().build().unwrap()).await.unwrap();
+			let reader = accepted.accept_stream().await.unwrap();
+			writer.write(Bytes::from_static(b"before ")).await.unwrap();
+
+			// 线路断了也能继续写，写下的等重连后补发
+			old.disconnect();
+			writer.write(Bytes::from_static(b"during ")).await.unwrap();
+
+			// 换到一条新的线路上，会话和流都接着用
+			let (left, right) = memory::pair();
+			server.reattach(Arc::new(right)).await.unwrap();
+			let resumed = client.reattach(Arc::new(left)).await.unwrap();
+			assert_eq!(resumed, vec![opened.id()]);
+
+			let (written, read) = tokio::join!(
+				async {
+					writer.write(Bytes::from_static(b"after")).await?;
+					writer.flush().await
+				},
+				reader.read_to_end()
+			);
+			written.unwrap();
+			assert_eq!(read.unwrap(), Bytes::from_static(b"before during after"));
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn chunked_stream() {
+		let (left, right) = memory::pair();
+		let (client, server) = links_on(Arc::new(left), Arc::new(right), options().stream_chunk_size(1000).build().unwrap());
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = sessions(&client, &server).await;
+			let writer = opened.open_stream(stream::OpenOptionsBuilder::default().build().unwrap()).await.unwrap();
+			let reader = accepted.accept_stream().await.unwrap();
+
+			// 每次写入各自切块，序号接着上一次
+			let data = (0..10_000u32).map(|index| index as u8).collect::<Vec<_>>();
+			let (written, chunks) = tokio::join!(
+				async {
+					for part in data.chunks(3000) {
+						writer.write(Bytes::copy_from_slice(part)).await?;
+					}
+					writer.flush().await
+				},
+				async {
+					let mut chunks = vec![];
+					while let Some(chunk) = reader.read().await.unwrap() {
+						chunks.push(chunk);
+					}
+					chunks
+				}
+			);
+			written.unwrap();
+
+			assert_eq!(chunks.len(), 10);
+			assert!(chunks.iter().all(|chunk| chunk.data.len() <= 1000));
+			assert!(chunks.iter().enumerate().all(|(index, chunk)| chunk.order == UBig::from(index)));
+			assert_eq!(chunks.iter().flat_map(|chunk| chunk.data.to_vec()).collect::<Vec<_>>(), data);
+			assert_eq!(writer.progress().borrow().done, data.len() as u64);
+			assert_eq!(reader.progress().borrow().done, data.len() as u64);
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn backpressure() {
+		let (left, right) = memory::pair();
+		let options = options()
+			.stream_high_water(64 * 1024)
+			.stream_low_water(16 * 1024)
+			.stream_chunk_size(4096)
+			.build()
+			.unwrap();
+		let (client, server) = links_on(Arc::new(left), Arc::new(right), options);
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = sessions(&client, &server).await;
+			let writer = opened.open_stream(stream::OpenOptionsBuilder::default().build().unwrap()).await.unwrap();
+			let reader = accepted.accept_stream().await.unwrap();
+
+			let data = Bytes::from(vec![7u8; 4 * 1024 * 1024]);
+			let done = std::cell::Cell::new(false);
+			let (written, read) = tokio::join!(
+				async {
+					writer.write(data.clone()).await?;
+					done.set(true);
+					writer.flush().await
+				},
+				async {
+					// 先不读，写入方过了高水位就该停下
+					tokio::time::sleep(Duration::from_millis(200)).await;
+					let received = reader.progress().borrow().done;
+					assert!(!done.get());
+					assert!(received >= 64 * 1024);
+					assert!(received < data.len() as u64 / 2);
+
+					// 读到低水位以下，对方继续
+					reader.read_to_end().await
+				}
+			);
+			written.unwrap();
+			assert_eq!(read.unwrap(), data);
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn lack_retransmit() {
+		let wire = memory::MemoryOptionsBuilder::default().loss(0.05).seed(10).build().unwrap();
+		let (left, right) = memory::pair_with_options(wire);
+		let options = options()
+			.stream_chunk_size(1024)
+			.stream_send_window(32 * 1024)
+			.build()
+			.unwrap();
+		let (client, server) = links_on(Arc::new(left), Arc::new(right), options);
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = sessions(&client, &server).await;
+
+			// 丢掉的分块由 Lack 要回，未确认的不超过发送窗口
+			let data = (0..256 * 1024u32).map(|index| (index % 251) as u8).collect::<Bytes>();
+			let options = stream::OpenOptionsBuilder::default().enforce_orderliness(false).build().unwrap();
+			let writer = accepted.open_buffer(options, data.len() as u64).await.unwrap();
+			let reader = opened.accept_stream().await.unwrap();
+			let (written, read) = tokio::join!(
+				async {
+					writer.write(data.clone()).await?;
+					writer.flush().await
+				},
+				reader.read_to_end()
+			);
+			written.unwrap();
+			assert_eq!(read.unwrap(), data);
+			assert_eq!(writer.progress().borrow().done, data.len() as u64);
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn ordered_reassembly() {
+		let wire = memory::MemoryOptionsBuilder::default().loss(0.02).reorder(0.3).seed(11).build().unwrap();
+		let (left, right) = memory::pair_with_options(wire);
+		// 线路扣下的最后一次写入要等下一次写入才送出，心跳勤一些，请求也多等一会
+		let options = options()
+			.stream_chunk_size(1024)
+			.stream_reorder_limit(4 * 1024)
+			.ping_interval(Duration::from_secs(1))
+			.request_timeout(Duration::from_secs(20))
+			.build()
+			.unwrap();
+		let (client, server) = links_on(Arc::new(left), Arc::new(right), options);
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = sessions(&client, &server).await;
+			let options = stream::OpenOptionsBuilder::default().enforce_orderliness(true).build().unwrap();
+			let writer = opened.open_stream(options).await.unwrap();
+			let reader = accepted.accept_stream().await.unwrap();
+
+			// 乱序、丢失和超出暂存上限的分块都要按序交出
+			let data = (0..128 * 1024u32).map(|index| (index % 251) as u8).collect::<Bytes>();
+			let (written, chunks) = tokio::join!(
+				async {
+					writer.write(data.clone()).await?;
+					writer.flush().await
+				},
+				async {
+					let mut chunks = vec![];
+					while let Some(chunk) = reader.read().await.unwrap() {
+						chunks.push(chunk);
+					}
+					chunks
+				}
+			);
+			written.unwrap();
+
+			assert!(chunks.iter().enumerate().all(|(index, chunk)| chunk.order == UBig::from(index)));
+			assert_eq!(chunks.iter().flat_map(|chunk| chunk.data.to_vec()).collect::<Bytes>(), data);
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn best_effort() {
+		let wire = memory::MemoryOptionsBuilder::default().loss(0.1).seed(12).build().unwrap();
+		let (left, right) = memory::pair_with_options(wire);
+		let options = options()
+			.stream_chunk_size(1024)
+			.request_timeout(Duration::from_secs(20))
+			.build()
+			.unwrap();
+		let (client, server) = links_on(Arc::new(left), Arc::new(right), options);
+
+		client.run_until(server.run_until(async {
+			let (opened, accepted) = sessions(&client, &server).await;
+			let options = stream::OpenOptionsBuilder::default().enforce_integrity(false).build().unwrap();
+			let writer = opened.open_stream(options).await.unwrap();
+			let reader = accepted.accept_stream().await.unwrap();
+
+			// 丢了的分块不重发，由 lost 报告
+			let data = Bytes::from(vec![12u8; 100 * 1024]);
+			let (written, orders) = tokio::join!(
+				async {
+					writer.write(data.clone()).await?;
+					writer.flush().await
+				},
+				async {
+					let mut orders = vec![];
+					while let Some(chunk) = reader.read().await.unwrap() {
+						orders.push(chunk.order);
+					}
+					orders
+				}
+			);
+			written.unwrap();
+
+			let lost = reader.lost();
+			assert!(!lost.is_empty());
+			assert!(lost.iter().all(|order| !orders.contains(order)));
+			assert_eq!(orders.len() + lost.len(), 100);
+			assert_eq!(writer.progress().borrow().done, data.len() as u64);
+		})).await;
+	}
+
+	#[tokio::test]
+	async fn id_parity() {
+		let (client, server) = links();
+		let even = |id: &UBig| id % 2u8 == 0;
+
+		client.run_until(server.run_until(async {
+			// 客户端分配偶数，服务端分配奇数，两边不会撞上
+			let (first, _) = sessions(&client, &server).await;
+			let (second, _) = sessions(&client, &server).await;
+			assert!(even(&first.id()) && even(&second.id()));
+			assert_ne!(first.id(), second.id());
+
+			let (opened, accepted) = tokio::join!(
+				server.create_session(session::OpenOptionsBuilder::default().build().unwrap()),
+				client.wait_session()
+			);
+			let (opened, accepted) = (opened.unwrap(), accepted.unwrap());
+			assert!(!even(&opened.id()));
+			assert_eq!(opened.id(), accepted.id());
+
+			// 同一会话里的流也按链接上的角色分奇偶
+			let options = stream::OpenOptionsBuilder::default().build().unwrap();
+			let from_server = opened.open_stream(options.clone()).await.unwrap();
+			let from_client = accepted.open_stream(options).await.unwrap();
+			assert!(!even(&from_server.stream().id()));
+			assert!(even(&from_client.stream().id()));
+
+			assert!(even(&client.context.event_id.next()));
+			assert!(!even(&server.context.event_id.next()));
+		})).await;
+	}
 }
\ No newline at end of file
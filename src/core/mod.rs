@@ -1,3 +1,8 @@
 pub mod packet;
 pub mod strategy;
-pub mod link;
\ No newline at end of file
+pub mod link;
+pub mod health;
+pub mod pending;
+pub mod dispatch;
+pub mod session;
+pub mod stream;
\ No newline at end of file
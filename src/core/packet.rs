@@ -1,6 +1,4 @@
 use ibig::UBig;
-use once_cell::sync::Lazy;
-use forever_safer::atomic_poll::AtomicPoll;
 
 use crate::protocol;
 
@@ -94,6 +92,7 @@ pub mod channel {
 
 		#[derive(Clone)]
 		pub struct Flush {
+			// 发送的分块数量
 			pub length: UBig,
 		}
 
@@ -152,6 +151,14 @@ pub fn handle_flatbuffer<'a>(packet: crate::protocol::packet::Packet<'a>, link_i
 	use crate::protocol;
 	use self::channel;
 
+	// 解析 Reason
+	fn quickly_reason(reason: protocol::value::Reason) -> Reason {
+		Reason {
+			code: reason.code(),
+			message: reason.message().map(|message| message.to_string())
+		}
+	}
+
 	// 快速获取 UBig
 	fn quickly_ubig<'a>(try_value: Option<protocol::value::UBig<'a>>) -> Option<UBig> {
 		if let Some(value) = try_value {
@@ -233,7 +240,7 @@ pub fn handle_flatbuffer<'a>(packet: crate::protocol::packet::Packet<'a>, link_i
 				} else if let Some(response) = $payload.response_as_reject()
 				&& let Some(reason) = response.reason() {
 					// 拒绝
-					Acceptable::Reject(Reason { code: reason.code() })
+					Acceptable::Reject(quickly_reason(reason))
 				} else {
 					// 无效包
 					return None;
@@ -305,7 +312,7 @@ pub fn handle_flatbuffer<'a>(packet: crate::protocol::packet::Packet<'a>, link_i
 		},
 		// 强制关闭
 		Head::SessionDeath		=> if let Some(payload) = packet.payload_as_session_death() {
-			let data = impl_data!(WrapEvent::Session(SessionEvent::Death(Reason { code: payload.reason().code() })));
+			let data = impl_data!(WrapEvent::Session(SessionEvent::Death(quickly_reason(payload.reason()))));
 			return Some(data);
 		},
 		// 整包数据
@@ -358,7 +365,7 @@ pub fn handle_flatbuffer<'a>(packet: crate::protocol::packet::Packet<'a>, link_i
 		// 请求重连流
 		Head::StreamReopen		=> quickly_none!(WrapEvent::Stream(StreamEvent::Reopen)),
 		// 响应重连流
-		Head::StreamReopenAck	=> if let Some(payload) = packet.payload_as_session_close_ack() {
+		Head::StreamReopenAck	=> if let Some(payload) = packet.payload_as_stream_reopen_ack() {
 			quickly_response!(payload, |response| WrapEvent::Stream(StreamEvent::ReopenAck(response)));
 		},
 		// 传输分块
@@ -441,7 +448,7 @@ pub fn handle_flatbuffer<'a>(packet: crate::protocol::packet::Packet<'a>, link_i
 		Head::StreamLater		=> quickly_none!(WrapEvent::Stream(StreamEvent::Later)),
 		Head::StreamGo			=> quickly_none!(WrapEvent::Stream(StreamEvent::Go)),
 		Head::StreamClear		=> if let Some(payload) = packet.payload_as_stream_clear() {
-			let data = impl_data!(WrapEvent::Stream(StreamEvent::Clear(Reason { code: payload.reason().code() })));
+			let data = impl_data!(WrapEvent::Stream(StreamEvent::Clear(quickly_reason(payload.reason()))));
 			return Some(data);
 		},
 		Head::HealthPing		=> quickly_none!(WrapEvent::Link(LinkEvent::Health(Health::Ping))),
@@ -454,26 +461,16 @@ pub fn handle_flatbuffer<'a>(packet: crate::protocol::packet::Packet<'a>, link_i
 }
 
 
-const EVENT_ID: Lazy<AtomicPoll> = Lazy::new(|| AtomicPoll::new());
-const U64_MAX: Lazy<UBig> = Lazy::new(|| UBig::from(u64::MAX));
-
-pub fn get_event_id() -> UBig {
-	EVENT_ID.get_and_increase()
-}
-
 pub fn serialize_datagram<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>, data: self::channel::Datagram) -> flatbuffers::WIPOffset<protocol::packet::Packet<'a>> {
 	use flatbuffers::{FlatBufferBuilder, WIPOffset, UnionWIPOffset, Vector};
 
 	let id = {
-		let event_id = match data.id.event {
-			Some(id) => id,
-			None => get_event_id()
-		};
+		let event_id = data.id.event;
 		let session_id = data.id.session;
 		let stream_id = data.id.stream;
 
 		self::channel::IdSet {
-			event: Some(event_id),
+			event: event_id,
 			session: session_id,
 			stream: stream_id
 		}
@@ -488,8 +485,12 @@ pub fn serialize_datagram<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>,
 	// 处理 Reason
 	fn handle_reason<'a>(builder: &mut FlatBufferBuilder<'a>, my_reason: self::Reason) -> WIPOffset<protocol::value::Reason<'a>> {
 		use crate::protocol::value::ReasonBuilder;
+		let message = my_reason.message.map(|message| builder.create_string(&message));
 		let mut reason_builder = ReasonBuilder::new(builder);
 		reason_builder.add_code(my_reason.code);
+		if let Some(message) = message {
+			reason_builder.add_message(message);
+		}
 		let reason = reason_builder.finish();
 
 		return reason;
@@ -519,8 +520,7 @@ pub fn serialize_datagram<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>,
 	// 生成 UBig
 	fn handle_ubig<'a>(builder: &mut FlatBufferBuilder<'a>, may_value: Option<UBig>) -> WIPOffset<protocol::value::UBig<'a>> {
 		use protocol::value::{UBigBuilder, UBigUnion, UInt64Builder, BytesBuilder};
-		let (from_type, from) = if let Some(value) = may_value.clone() && value <= U64_MAX.clone() {
-			let number = u64::try_from(value).unwrap();
+		let (from_type, from) = if let Some(number) = may_value.clone().and_then(|value| u64::try_from(value).ok()) {
 			let mut uint64_builder = UInt64Builder::new(builder);
 			uint64_builder.add_uint(number);
 			let uint64 = uint64_builder.finish();
@@ -637,10 +637,8 @@ pub fn serialize_datagram<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>,
 						return (Head::SessionCloseAck, (Payload::Session_CloseAck, ack));
 					},
 					Event::Death(reason) => {
-						use protocol::{session::DeathBuilder, value::ReasonBuilder};
-						let mut reason_builder = ReasonBuilder::new(builder);
-						reason_builder.add_code(reason.code);
-						let reason = reason_builder.finish();
+						use protocol::session::DeathBuilder;
+						let reason = handle_reason(builder, reason);
 
 						let mut death_builder = DeathBuilder::new(builder);
 						death_builder.add_reason(reason);
@@ -759,10 +757,8 @@ pub fn serialize_datagram<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>,
 						return (Head::StreamGo, quickly_none_payload(builder));
 					},
 					Event::Clear(reason) => {
-						use protocol::{stream::ClearBuilder, value::ReasonBuilder};
-						let mut reason_builder = ReasonBuilder::new(builder);
-						reason_builder.add_code(reason.code);
-						let reason = reason_builder.finish();
+						use protocol::stream::ClearBuilder;
+						let reason = handle_reason(builder, reason);
 
 						let mut clear_builder = ClearBuilder::new(builder);
 						clear_builder.add_reason(reason);
@@ -826,6 +822,15 @@ pub fn serialize_datagram<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>,
 	packet
 }
 
+/// Serialize a single datagram into a finished flatbuffer.
+pub fn encode_datagram(data: self::channel::Datagram) -> bytes::Bytes {
+	let mut builder = flatbuffers::FlatBufferBuilder::new();
+	let packet = serialize_datagram(&mut builder, data);
+	builder.finish(packet, None);
+
+	bytes::Bytes::copy_from_slice(builder.finished_data())
+}
+
 pub fn serialize_datagrams<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>, datas: Vec<self::channel::Datagram>) -> flatbuffers::WIPOffset<protocol::packet::MutPacket<'a>> {
 	use protocol::packet::MutPacketBuilder;
 
@@ -841,12 +846,137 @@ pub fn serialize_datagrams<'a>(builder: &mut flatbuffers::FlatBufferBuilder<'a>,
 	builder.finish()
 }
 
-#[derive(Clone)]
+/// Why something was rejected or ended, as sent on the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Reason {
 	pub code: u64,
+	/// Optional human readable explanation.
+	pub message: Option<String>,
+}
+
+/// Well-known reason codes.
+///
+/// Codes from `ReasonCode::APPLICATION_BASE` upwards are left for applications.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReasonCode {
+	/// Closed on purpose, nothing went wrong.
+	Normal,
+	/// Refused by the strategy of the other party.
+	Rejected,
+	/// The other party broke the protocol.
+	ProtocolError,
+	/// The event is not allowed in the current state.
+	IllegalState,
+	/// The other party stopped answering in time.
+	Timeout,
+	/// The other party has too much to handle.
+	Overloaded,
+	/// The other party speaks a version we do not understand.
+	UnsupportedVersion,
+	/// Data was sent against the direction of the session.
+	WrongDirection,
+	/// The other party is shutting down.
+	GoingAway,
+	/// The transport dropped and this cannot be resumed.
+	Disconnected,
+	/// Defined by the application, relative to `APPLICATION_BASE`, up to `APPLICATION_MAX`.
+	Application(u64),
+	/// Reserved for future use by the protocol.
+	Unknown(u64)
+}
+
+impl ReasonCode {
+	/// First code of the range left for applications.
+	pub const APPLICATION_BASE: u64 = 1 << 16;
+	/// Largest application code that still fits on the wire.
+	pub const APPLICATION_MAX: u64 = u64::MAX - Self::APPLICATION_BASE;
+
+	pub const fn code(&self) -> u64 {
+		match self {
+			Self::Normal => 0,
+			Self::Rejected => 1,
+			Self::ProtocolError => 2,
+			Self::IllegalState => 3,
+			Self::Timeout => 4,
+			Self::Overloaded => 5,
+			Self::UnsupportedVersion => 6,
+			Self::WrongDirection => 7,
+			Self::GoingAway => 8,
+			Self::Disconnected => 9,
+			Self::Application(code) => Self::APPLICATION_BASE.saturating_add(*code),
+			Self::Unknown(code) => *code
+		}
+	}
+}
+
+impl From<u64> for ReasonCode {
+	fn from(code: u64) -> Self {
+		match code {
+			0 => Self::Normal,
+			1 => Self::Rejected,
+			2 => Self::ProtocolError,
+			3 => Self::IllegalState,
+			4 => Self::Timeout,
+			5 => Self::Overloaded,
+			6 => Self::UnsupportedVersion,
+			7 => Self::WrongDirection,
+			8 => Self::GoingAway,
+			9 => Self::Disconnected,
+			code if code >= Self::APPLICATION_BASE => Self::Application(code - Self::APPLICATION_BASE),
+			code => Self::Unknown(code)
+		}
+	}
+}
+
+impl Reason {
+	pub const NORMAL: Reason = Reason::of(ReasonCode::Normal);
+	pub const REJECTED: Reason = Reason::of(ReasonCode::Rejected);
+	pub const PROTOCOL_ERROR: Reason = Reason::of(ReasonCode::ProtocolError);
+	pub const ILLEGAL_STATE: Reason = Reason::of(ReasonCode::IllegalState);
+	pub const TIMEOUT: Reason = Reason::of(ReasonCode::Timeout);
+	pub const OVERLOADED: Reason = Reason::of(ReasonCode::Overloaded);
+	pub const UNSUPPORTED_VERSION: Reason = Reason::of(ReasonCode::UnsupportedVersion);
+	pub const WRONG_DIRECTION: Reason = Reason::of(ReasonCode::WrongDirection);
+	pub const GOING_AWAY: Reason = Reason::of(ReasonCode::GoingAway);
+	pub const DISCONNECTED: Reason = Reason::of(ReasonCode::Disconnected);
+
+	pub const fn of(kind: ReasonCode) -> Reason {
+		Reason { code: kind.code(), message: None }
+	}
+
+	/// A reason from the range left for applications,
+	/// `None` if `code` is above `ReasonCode::APPLICATION_MAX`.
+	pub fn application(code: u64) -> Option<Self> {
+		match code <= ReasonCode::APPLICATION_MAX {
+			true => Some(Self::of(ReasonCode::Application(code))),
+			false => None
+		}
+	}
+
+	/// Attach a human readable explanation.
+	pub fn with_message(mut self, message: impl Into<String>) -> Self {
+		self.message = Some(message.into());
+		self
+	}
+
+	pub fn kind(&self) -> ReasonCode {
+		ReasonCode::from(self.code)
+	}
+}
+
+impl std::fmt::Display for Reason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{:?} ({})", self.kind(), self.code)?;
+		if let Some(message) = &self.message {
+			write!(f, ": {}", message)?;
+		}
+
+		Ok(())
+	}
 }
 
 /// How to send data packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TransWays {
 	/// Send a whole piece of data and complete it all at once.
 	Block,
@@ -854,4 +984,60 @@ pub enum TransWays {
 	Buffer,
 	/// Sending an unpredictable total amount of data.
 	Stream
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reason_codes() {
+		let kinds = [
+			ReasonCode::Normal,
+			ReasonCode::Rejected,
+			ReasonCode::ProtocolError,
+			ReasonCode::IllegalState,
+			ReasonCode::Timeout,
+			ReasonCode::Overloaded,
+			ReasonCode::UnsupportedVersion,
+			ReasonCode::WrongDirection,
+			ReasonCode::GoingAway,
+			ReasonCode::Disconnected,
+			ReasonCode::Application(0),
+			ReasonCode::Application(ReasonCode::APPLICATION_MAX),
+			ReasonCode::Unknown(10)
+		];
+		for kind in kinds {
+			assert_eq!(ReasonCode::from(kind.code()), kind);
+			assert_eq!(Reason::of(kind).kind(), kind);
+		}
+
+		// 超出范围的应用代码无法原样传回
+		assert_eq!(Reason::application(ReasonCode::APPLICATION_MAX).map(|reason| reason.code), Some(u64::MAX));
+		assert_eq!(Reason::application(ReasonCode::APPLICATION_MAX + 1), None);
+	}
+
+	#[test]
+	fn reason_message() {
+		use channel::{Datagram, Event, IdSet, link::Event as LinkEvent, session::Event as SessionEvent};
+
+		let reason = Reason::application(42).unwrap().with_message("quota exceeded");
+		let data = Datagram {
+			id: IdSet {
+				event: Some(UBig::from(1u8)),
+				session: Some(UBig::from(2u8)),
+				stream: None
+			},
+			event: Event::Link(LinkEvent::SessionAck(SessionEvent::Death(reason.clone())))
+		};
+
+		// 编码后再解出来，代码和说明都不变
+		let buffer = encode_datagram(data);
+		let packet = flatbuffers::root::<protocol::packet::Packet>(&buffer).unwrap();
+		let Some(Datagram { event: Event::Session(SessionEvent::Death(decoded)), .. }) = handle_flatbuffer(packet, 0) else {
+			panic!("not a Death");
+		};
+		assert_eq!(decoded, reason);
+		assert_eq!(decoded.kind(), ReasonCode::Application(42));
+	}
 }
\ No newline at end of file
@@ -0,0 +1,177 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::Duration};
+use dashmap::DashMap;
+use ibig::UBig;
+use tokio_with_wasm::alias::{sync::{mpsc, oneshot}, time::timeout};
+
+use super::link::LinkError;
+use super::packet::{channel, Reason};
+
+/// How long to wait for an Ack before sending a request again.
+pub const RESEND_INTERVAL: Duration = Duration::from_secs(3);
+/// How many requests of the other party are remembered to answer duplicates.
+const REPLY_HISTORY: usize = 1024;
+
+/// Requests sent by this side that are waiting for their Ack, keyed by event id.
+#[derive(Default)]
+pub(crate) struct PendingRequests {
+	waiters: DashMap<UBig, oneshot::Sender<Result<channel::Datagram, LinkError>>>
+}
+
+impl PendingRequests {
+	/// Register `event_id` before sending the request, so the Ack cannot be missed.
+	pub fn register(self: &Arc<Self>, event_id: UBig) -> PendingResponse {
+		let (sender, receiver) = oneshot::channel();
+		self.waiters.insert(event_id.clone(), sender);
+
+		PendingResponse {
+			event_id,
+			receiver,
+			requests: self.clone()
+		}
+	}
+
+	/// Hand `data` to the request waiting for it, if it is an Ack someone waits for.
+	pub fn resolve(&self, data: &channel::Datagram) -> bool {
+		use channel::{Event as WrapEvent, session::Event as SessionEvent, stream::Event as StreamEvent};
+
+		// 只有对方发来的回应才算
+		let response = matches!(
+			&data.event,
+			WrapEvent::Session(SessionEvent::OpenAck(_) | SessionEvent::ReopenAck(_) | SessionEvent::CloseAck(_))
+			| WrapEvent::Stream(
+				StreamEvent::OpenAck(_) | StreamEvent::ReopenAck(_) | StreamEvent::BlockAck | StreamEvent::FlushAck | StreamEvent::Clear(_)
+			)
+		);
+		if !response {
+			return false;
+		}
+
+		let Some(event_id) = data.id.event.as_ref() else {
+			return false;
+		};
+
+		match self.waiters.remove(event_id) {
+			Some((_, sender)) => sender.send(Ok(data.clone())).is_ok(),
+			None => false
+		}
+	}
+
+	/// Fail the request `event_id` with `error`, such as when it could not be sent.
+	pub fn fail(&self, event_id: &UBig, error: LinkError) -> bool {
+		match self.waiters.remove(event_id) {
+			Some((_, sender)) => sender.send(Err(error)).is_ok(),
+			None => false
+		}
+	}
+}
+
+/// The Ack of one request, removed from the registry when dropped.
+pub(crate) struct PendingResponse {
+	event_id: UBig,
+	receiver: oneshot::Receiver<Result<channel::Datagram, LinkError>>,
+	requests: Arc<PendingRequests>
+}
+
+impl PendingResponse {
+	/// Wait up to `deadline` for the Ack, `pick` takes what it expects out of it.
+	///
+	/// Can be called again after a `Timeout` when the request is sent again.
+	pub async fn wait<T>(&mut self, deadline: Duration, pick: impl FnOnce(channel::Datagram) -> Option<T>) -> Result<T, LinkError> {
+		match timeout(deadline, &mut self.receiver).await {
+			Ok(Ok(Ok(data))) => pick(data).ok_or(LinkError::Protocol(Reason::PROTOCOL_ERROR)),
+			Ok(Ok(Err(error))) => Err(error),
+			Ok(Err(_)) => Err(LinkError::Closed),
+			Err(_) => Err(LinkError::Timeout)
+		}
+	}
+
+	/// Send `data` and wait up to `deadline` for the Ack,
+	/// sending it again with the same event id every `RESEND_INTERVAL`.
+	pub async fn resend<T>(
+		&mut self,
+		sender: &mpsc::UnboundedSender<channel::Datagram>,
+		data: channel::Datagram,
+		deadline: Duration,
+		pick: impl Fn(channel::Datagram) -> Option<T>
+	) -> Result<T, LinkError> {
+		let attempts = async {
+			loop {
+				sender.send(data.clone()).map_err(|_| LinkError::Closed)?;
+
+				match self.wait(RESEND_INTERVAL.min(deadline), &pick).await {
+					// 请求或回应丢了，原样重发
+					Err(LinkError::Timeout) => continue,
+					result => return result
+				}
+			}
+		};
+
+		timeout(deadline, attempts).await.unwrap_or(Err(LinkError::Timeout))
+	}
+}
+
+impl Drop for PendingResponse {
+	fn drop(&mut self) {
+		self.requests.waiters.remove(&self.event_id);
+	}
+}
+
+/// What this side did with a request of the other party.
+pub(crate) enum Reply {
+	/// Seen for the first time, handle it.
+	Fresh,
+	/// Still being decided, the Ack follows.
+	Pending,
+	/// Already answered, send the same Ack again.
+	Answered(channel::Datagram)
+}
+
+/// Requests of the other party seen by this side, keyed by event id.
+///
+/// A request sent again because its Ack was lost is answered with the same Ack
+/// instead of being handled twice.
+#[derive(Default)]
+pub(crate) struct Replies {
+	inner: Mutex<RepliesInner>
+}
+
+#[derive(Default)]
+struct RepliesInner {
+	answers: HashMap<UBig, Option<channel::Datagram>>,
+	history: VecDeque<UBig>
+}
+
+impl Replies {
+	/// Look up `event_id`, a fresh one is remembered as pending.
+	pub fn check(&self, event_id: &UBig) -> Reply {
+		let Ok(mut inner) = self.inner.lock() else {
+			return Reply::Fresh;
+		};
+
+		match inner.answers.get(event_id) {
+			Some(Some(answer)) => return Reply::Answered(answer.clone()),
+			Some(None) => return Reply::Pending,
+			None => {}
+		}
+
+		inner.answers.insert(event_id.clone(), None);
+		inner.history.push_back(event_id.clone());
+		if inner.history.len() > REPLY_HISTORY
+		&& let Some(expired) = inner.history.pop_front() {
+			inner.answers.remove(&expired);
+		}
+
+		Reply::Fresh
+	}
+
+	/// Remember the Ack sent for a request, under the event id it carries.
+	pub fn answer(&self, data: &channel::Datagram) {
+		let (Some(event_id), Ok(mut inner)) = (data.id.event.as_ref(), self.inner.lock()) else {
+			return;
+		};
+
+		if let Some(answer) = inner.answers.get_mut(event_id) {
+			*answer = Some(data.clone());
+		}
+	}
+}
\ No newline at end of file
@@ -0,0 +1,462 @@
+use std::sync::Arc;
+use bytes::Bytes;
+use ibig::UBig;
+use tokio_with_wasm::alias::{sync::watch, time::timeout};
+
+use super::dispatch::Inbox;
+use super::link::{ends_session, Ended, IdAllocator, InnerChannel, InnerContext, LinkError, Side};
+use super::pending::{Replies, Reply};
+use super::packet::{channel, Reason};
+use super::strategy::Acceptable;
+use super::stream::{Stream, StreamReader, StreamWriter};
+
+// 会话中一个尚未完成的流，结束时自动减少计数
+pub(crate) struct PendingStream(Arc<watch::Sender<usize>>);
+
+impl PendingStream {
+	fn new(pending: &Arc<watch::Sender<usize>>) -> Self {
+		pending.send_modify(|count| *count += 1);
+		Self(pending.clone())
+	}
+}
+
+impl Drop for PendingStream {
+	fn drop(&mut self) {
+		self.0.send_modify(|count| *count = count.saturating_sub(1));
+	}
+}
+
+/// A session negotiated on a link.
+#[derive(Clone)]
+pub struct Session {
+	id: UBig,
+	options: channel::session::OpenOptions,
+	// 会话由哪一方发起，决定 `Ways` 的方向
+	opener: Side,
+	channel: InnerChannel,
+	context: InnerContext,
+	stream_id: Arc<IdAllocator>,
+	streams: flume::Receiver<StreamReader>,
+	blocks: flume::Receiver<Bytes>,
+	pending: Arc<watch::Sender<usize>>,
+	ended: Ended
+}
+
+impl Session {
+	pub(crate) fn new(
+		id: UBig,
+		options: channel::session::OpenOptions,
+		opener: Side,
+		channel: InnerChannel,
+		context: InnerContext,
+		receiver: Inbox
+	) -> Self {
+		let (stream_sender, streams) = flume::unbounded::<StreamReader>();
+		let (block_sender, blocks) = flume::unbounded::<Bytes>();
+		let readable = can_read(&options.way, opener);
+		let ended = Ended::default();
+		let (pending, drained) = watch::channel(0usize);
+		let pending = Arc::new(pending);
+		context.session_drains.insert(id.clone(), drained);
+
+		context.runtime.spawn_local(session_handler(
+			id.clone(),
+			receiver,
+			channel.clone(),
+			context.clone(),
+			readable,
+			stream_sender,
+			block_sender,
+			pending.clone(),
+			ended.clone()
+		));
+
+		let stream_id = Arc::new(IdAllocator::new(&context.info.mode));
+		Self {
+			id,
+			options,
+			opener,
+			channel,
+			context,
+			stream_id,
+			streams,
+			blocks,
+			pending,
+			ended
+		}
+	}
+
+	pub fn id(&self) -> UBig {
+		self.id.clone()
+	}
+
+	pub fn options(&self) -> &channel::session::OpenOptions {
+		&self.options
+	}
+
+	/// Whether this side may open streams and send blocks.
+	pub fn can_write(&self) -> bool {
+		can_write(&self.options.way, self.opener)
+	}
+
+	/// Whether this side may accept streams and receive blocks.
+	pub fn can_read(&self) -> bool {
+		can_read(&self.options.way, self.opener)
+	}
+
+	// 快速生成 ID
+	fn id_set(&self, event_id: UBig, stream_id: Option<UBig>) -> channel::IdSet {
+		channel::IdSet {
+			event: Some(event_id),
+			session: Some(self.id.clone()),
+			stream: stream_id
+		}
+	}
+
+	/// Request the other party to open a new stream, this side writes into it.
+	///
+	/// Fails with `Rejected` if the other party refused it,
+	/// or with `Protocol` if the session does not allow this side to write.
+	pub async fn open_stream(&self, options: channel::stream::OpenOptions) -> Result<StreamWriter, LinkError> {
+		self.open(options, None).await
+	}
+
+	/// Like `open_stream`, but announces the total `length` in bytes so the other party can preallocate.
+	pub async fn open_buffer(&self, options: channel::stream::OpenOptions, length: u64) -> Result<StreamWriter, LinkError> {
+		self.open(options, Some(UBig::from(length))).await
+	}
+
+	// 发出前先检查会话能否写入
+	fn check_write(&self) -> Result<(), LinkError> {
+		if !self.can_write() {
+			return Err(LinkError::Protocol(Reason::WRONG_DIRECTION));
+		}
+
+		if self.context.disconnected.is_disconnected() {
+			return Err(LinkError::Disconnected);
+		}
+
+		Ok(())
+	}
+
+	async fn open(&self, options: channel::stream::OpenOptions, length: Option<UBig>) -> Result<StreamWriter, LinkError> {
+		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, stream::Event};
+
+		self.check_write()?;
+
+		let event_id = self.context.event_id.next();
+		let stream_id = self.stream_id.next();
+		let data = Datagram {
+			id: self.id_set(event_id.clone(), Some(stream_id.clone())),
+			event: WrapEvent::Link(LinkEvent::StreamAck(Event::Open { options: options.clone(), length: length.clone() }))
+		};
+
+		// 先订阅和登记，避免错过回应和后续的包
+		let receiver = self.channel.subscribe_stream(&self.id, &stream_id);
+		let mut response = self.context.requests.register(event_id);
+
+		let ack = response.resend(&self.channel.get_sender(), data, self.context.options.request_timeout, |data| match data.event {
+			WrapEvent::Stream(Event::OpenAck(ack)) if data.id.session.as_ref() == Some(&self.id) => Some(ack),
+			_ => None
+		}).await?;
+
+		match ack {
+			Acceptable::Accept => {
+				let stream = Stream::new(stream_id, self.id.clone(), options, length, self.context.event_id.clone());
+				Ok(StreamWriter::new(stream, self.channel.clone(), self.context.clone(), receiver, PendingStream::new(&self.pending)))
+			},
+			Acceptable::Reject(reason) => Err(reason.into())
+		}
+	}
+
+	/// Wait for the next stream opened by the other party, this side reads from it.
+	///
+	/// Fails with the reason the session ended once it is over,
+	/// or with `Protocol` if the session does not allow this side to read.
+	pub async fn accept_stream(&self) -> Result<StreamReader, LinkError> {
+		if !self.can_read() {
+			return Err(LinkError::Protocol(Reason::WRONG_DIRECTION));
+		}
+
+		self.streams.recv_async().await.map_err(|_| self.ended.get())
+	}
+
+	/// Send a whole piece of data without opening a stream.
+	///
+	/// With `ack`, resolves only after the other party confirmed it,
+	/// the block is sent again with the same event id until `LinkOptions::request_timeout`.
+	/// Fails with `Timeout` if it was never confirmed, with `Rejected` if the other party refused it,
+	/// or with `Protocol` if the session does not allow this side to write.
+	pub async fn send_block(&self, data: Bytes, ack: bool) -> Result<(), LinkError> {
+		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, stream::{Block, Event}};
+
+		self.check_write()?;
+
+		let event_id = self.context.event_id.next();
+		let data = Datagram {
+			id: self.id_set(event_id.clone(), None),
+			event: WrapEvent::Link(LinkEvent::StreamAck(Event::Block(Block { ask_response: ack, data })))
+		};
+
+		let sender = self.channel.get_sender();
+		if !ack {
+			return sender.send(data).map_err(|_| LinkError::Closed);
+		}
+
+		let mut response = self.context.requests.register(event_id);
+		let result = response.resend(&sender, data, self.context.options.request_timeout, |data| match data.event {
+			WrapEvent::Stream(Event::BlockAck) if data.id.session.as_ref() == Some(&self.id) => Some(Ok(())),
+			WrapEvent::Stream(Event::Clear(reason)) if data.id.session.as_ref() == Some(&self.id) => Some(Err(reason)),
+			_ => None
+		}).await?;
+
+		// 对方拒收
+		result.map_err(LinkError::from)
+	}
+
+	/// Wait for the next block sent by the other party.
+	///
+	/// Fails with the reason the session ended once it is over,
+	/// or with `Protocol` if the session does not allow this side to read.
+	pub async fn recv_block(&self) -> Result<Bytes, LinkError> {
+		if !self.can_read() {
+			return Err(LinkError::Protocol(Reason::WRONG_DIRECTION));
+		}
+
+		self.blocks.recv_async().await.map_err(|_| self.ended.get())
+	}
+
+	/// Close the session once its streams have finished.
+	///
+	/// Waits for the streams in both directions to flush, then runs the `Close` handshake.
+	/// Fails with `Rejected` if the other party refused to close,
+	/// or ends the session with `Death` and fails with `Timeout` after `LinkOptions::close_timeout`.
+	pub async fn close(&self) -> Result<(), LinkError> {
+		close_session(&self.channel, &self.context, &self.id).await
+	}
+}
+
+/// Drain the streams of a session and close it, with `Death` as the fallback.
+pub(crate) async fn close_session(channel: &InnerChannel, context: &InnerContext, session_id: &UBig) -> Result<(), LinkError> {
+	use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, session::Event, IdSet};
+
+	let drained = context.session_drains.get(session_id).map(|drained| drained.clone());
+	let handshake = async {
+		// 等待进行中的流结束
+		if let Some(mut drained) = drained {
+			let _ = drained.wait_for(|count| *count == 0).await;
+		}
+
+		let event_id = context.event_id.next();
+		let data = Datagram {
+			id: IdSet {
+				event: Some(event_id.clone()),
+				session: Some(session_id.clone()),
+				stream: None
+			},
+			event: WrapEvent::Link(LinkEvent::SessionAck(Event::Close))
+		};
+
+		let mut response = context.requests.register(event_id);
+
+		let ack = response.resend(&channel.get_sender(), data, context.options.close_timeout, |data| match data.event {
+			WrapEvent::Session(Event::CloseAck(ack)) if data.id.session.as_ref() == Some(session_id) => Some(ack),
+			_ => None
+		}).await?;
+
+		match ack {
+			Acceptable::Accept => Ok(()),
+			Acceptable::Reject(reason) => Err(LinkError::from(reason))
+		}
+	};
+
+	let result = match timeout(context.options.close_timeout, handshake).await {
+		Ok(result) => result,
+		Err(_) => Err(LinkError::Timeout)
+	};
+
+	// 等不及了，直接结束
+	if let Err(LinkError::Timeout) = result {
+		kill_session(channel, context, session_id.clone(), Reason::TIMEOUT.with_message("close timed out"));
+	}
+
+	result
+}
+
+/// End a session right away with `Death`.
+pub(crate) fn kill_session(channel: &InnerChannel, context: &InnerContext, session_id: UBig, reason: Reason) {
+	use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, session::Event, IdSet};
+
+	let _ = channel.get_sender().send(Datagram {
+		id: IdSet {
+			event: Some(context.event_id.next()),
+			session: Some(session_id),
+			stream: None
+		},
+		event: WrapEvent::Link(LinkEvent::SessionAck(Event::Death(reason)))
+	});
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn session_handler(
+	session_id: UBig,
+	receiver: Inbox,
+	channel: InnerChannel,
+	context: InnerContext,
+	readable: bool,
+	streams: flume::Sender<StreamReader>,
+	blocks: flume::Sender<Bytes>,
+	pending: Arc<watch::Sender<usize>>,
+	ended: Ended
+) {
+	use channel::{
+		Datagram,
+		Event as WrapEvent,
+		link::Event as LinkEvent,
+		stream::Event as StreamEvent
+	};
+
+	// 对方开启流的请求和发来的整包，重复的直接回应上一次的结果
+	let replies = Arc::new(Replies::default());
+
+	while let Ok(data) = receiver.recv_async().await {
+		// 会话结束了
+		if let Some(error) = ends_session(&data, &session_id) {
+			ended.set(error);
+			break;
+		}
+
+		let id = data.id;
+
+		match data.event {
+			// 对方请求开启流
+			WrapEvent::Stream(StreamEvent::Open { options, length }) => {
+				let Some(stream_id) = id.stream.clone() else {
+					continue;
+				};
+
+				// 对方没收到回应而重发的请求
+				if let Some(event_id) = &id.event {
+					match replies.check(event_id) {
+						Reply::Fresh => {},
+						Reply::Pending => continue,
+						Reply::Answered(answer) => {
+							let _ = channel.get_sender().send(answer);
+							continue;
+						}
+					}
+				}
+
+				// 方向不对，不必询问策略
+				if !readable {
+					let answer = Datagram {
+						id,
+						event: WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::OpenAck(Acceptable::Reject(Reason::WRONG_DIRECTION))))
+					};
+					replies.answer(&answer);
+					let _ = channel.get_sender().send(answer);
+					continue;
+				}
+
+				let strategy = context.strategy.clone();
+				let channel = channel.clone();
+				let the_context = context.clone();
+				let streams = streams.clone();
+				let session_id = session_id.clone();
+				let pending = pending.clone();
+				let replies = replies.clone();
+				context.runtime.spawn_local(async move {
+					let ack = strategy.ack_stream_open(&the_context.info, &session_id, &stream_id, &options, length.as_ref()).await;
+
+					// 先建立读取端再回应，避免错过分块
+					if let Acceptable::Accept = ack {
+						let receiver = channel.subscribe_stream(&session_id, &stream_id);
+						let stream = Stream::new(stream_id, session_id, options, length, the_context.event_id.clone());
+						let reader = StreamReader::new(stream, channel.clone(), the_context, receiver, PendingStream::new(&pending));
+						let _ = streams.send(reader);
+					}
+
+					let answer = Datagram {
+						id,
+						event: WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::OpenAck(ack)))
+					};
+					replies.answer(&answer);
+					let _ = channel.get_sender().send(answer);
+				});
+			},
+
+			// 对方发来整包
+			WrapEvent::Stream(StreamEvent::Block(block)) => {
+				// 方向不对，拒收
+				if !readable {
+					let _ = channel.get_sender().send(Datagram {
+						id,
+						event: WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::Clear(Reason::WRONG_DIRECTION)))
+					});
+					continue;
+				}
+
+				// 重复的按上一次的结果回应，对方可能没收到；还在决定的等结果出来再说
+				if let Some(event_id) = &id.event {
+					match replies.check(event_id) {
+						Reply::Fresh => {},
+						Reply::Pending => continue,
+						Reply::Answered(answer) => {
+							if block.ask_response || !matches!(answer.event, WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::BlockAck))) {
+								let _ = channel.get_sender().send(answer);
+							}
+							continue;
+						}
+					}
+				}
+
+				let strategy = context.strategy.clone();
+				let channel = channel.clone();
+				let the_context = context.clone();
+				let blocks = blocks.clone();
+				let session_id = session_id.clone();
+				let replies = replies.clone();
+				context.runtime.spawn_local(async move {
+					let event = match strategy.ack_block(&the_context.info, &session_id, &block.data).await {
+						Acceptable::Accept => {
+							let _ = blocks.send(block.data);
+							StreamEvent::BlockAck
+						},
+						Acceptable::Reject(reason) => StreamEvent::Clear(reason)
+					};
+
+					// 结果总要记下，拒收总要告知，接受的只在对方要求时回应
+					let answer = Datagram {
+						id,
+						event: WrapEvent::Link(LinkEvent::StreamAck(event))
+					};
+					replies.answer(&answer);
+					if block.ask_response || matches!(answer.event, WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::Clear(_)))) {
+						let _ = channel.get_sender().send(answer);
+					}
+				});
+			},
+
+			_ => {}
+		}
+	}
+}
+
+// 发起方按 `Ways` 的字面方向，接受方与之相反
+fn can_write(way: &channel::session::Ways, opener: Side) -> bool {
+	use channel::session::Ways;
+
+	matches!(
+		(way, opener),
+		(Ways::TwoWays, _) | (Ways::OnlyWrite, Side::Local) | (Ways::OnlyRead, Side::Remote)
+	)
+}
+
+fn can_read(way: &channel::session::Ways, opener: Side) -> bool {
+	use channel::session::Ways;
+
+	matches!(
+		(way, opener),
+		(Ways::TwoWays, _) | (Ways::OnlyRead, Side::Local) | (Ways::OnlyWrite, Side::Remote)
+	)
+}
\ No newline at end of file
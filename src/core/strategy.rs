@@ -1,4 +1,9 @@
-use super::packet::Reason;
+use std::{collections::HashMap, sync::Arc};
+use bytes::Bytes;
+use ibig::UBig;
+
+use super::link::LinkMode;
+use super::packet::{channel, Reason};
 
 /// Response to rejection or acceptance.
 #[derive(Clone)]
@@ -7,11 +12,43 @@ pub enum Acceptable {
 	Reject(Reason)
 }
 
+/// What a strategy knows about the link a request arrived on.
+#[derive(Clone)]
+pub struct LinkInfo {
+	pub mode: LinkMode,
+	/// Metadata about the other party, taken from `LinkOptions::peer`.
+	pub peer: Arc<HashMap<String, String>>
+}
+
 #[async_trait::async_trait]
-pub trait Strategy {
+pub trait Strategy: Send + Sync {
 	/// The other party requests to open a new session.
-	async fn ack_session_open(&self) -> Acceptable;
+	async fn ack_session_open(&self, link: &LinkInfo, session_id: &UBig, options: &channel::session::OpenOptions) -> Acceptable;
+
+	/// The other party requests to resume a session over a new transport.
+	///
+	/// Only asked for sessions that allow reconnecting.
+	async fn ack_session_reopen(&self, _link: &LinkInfo, _session_id: &UBig, _options: &channel::session::OpenOptions) -> Acceptable {
+		Acceptable::Accept
+	}
+
+	/// The other party requests to close a session.
+	async fn ack_session_close(&self, _link: &LinkInfo, _session_id: &UBig) -> Acceptable {
+		Acceptable::Accept
+	}
+
+	/// The other party requests to open a new stream, `length` is set for buffer transfers.
+	async fn ack_stream_open(
+		&self,
+		link: &LinkInfo,
+		session_id: &UBig,
+		stream_id: &UBig,
+		options: &channel::stream::OpenOptions,
+		length: Option<&UBig>
+	) -> Acceptable;
 
-	/// The other party requests to open a new stream.
-	async fn ack_stream_open(&self) -> Acceptable;
+	/// The other party sent a block, rejected ones are not delivered.
+	async fn ack_block(&self, _link: &LinkInfo, _session_id: &UBig, _data: &Bytes) -> Acceptable {
+		Acceptable::Accept
+	}
 }
\ No newline at end of file
@@ -0,0 +1,959 @@
+use std::{collections::{BTreeMap, BTreeSet}, sync::{Arc, Mutex}, time::Duration};
+use bytes::{Bytes, BytesMut};
+use ibig::UBig;
+use tokio_with_wasm::alias::{select, sync::{watch, Mutex as AsyncMutex}, time::{interval, timeout}};
+
+use super::dispatch::Inbox;
+use super::link::{ends_session, Ended, IdAllocator, InnerChannel, InnerContext, LinkError};
+use super::session::PendingStream;
+use super::pending::{PendingRequests, RESEND_INTERVAL};
+use super::packet::{channel, Reason, TransWays};
+use super::strategy::Acceptable;
+use super::packet::channel::stream::{Chunk, OpenOptions};
+
+/// Default of `LinkOptions::stream_chunk_size`.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+/// Largest buffer preallocated for a known-length stream.
+pub const PREALLOCATE_LIMIT: usize = 64 * 1024 * 1024;
+/// How long to wait for `FlushAck` before sending `Flush` again.
+pub const FLUSH_ACK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Most orders reported by a single `Lack`.
+pub const LACK_LIMIT: usize = 1024;
+/// Chunks still arriving after `Later` before it is sent again, in case it was lost.
+pub const LATER_RESEND_CHUNKS: usize = 64;
+/// How long a finished reader keeps answering repeated `Flush`.
+pub const FLUSH_LINGER: Duration = Duration::from_secs(FLUSH_ACK_TIMEOUT.as_secs() * 3);
+
+/// Bytes transferred on a stream, out of the announced total if there is one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+	pub done: u64,
+	pub total: Option<u64>
+}
+
+/// A stream opened inside a session.
+#[derive(Clone)]
+pub struct Stream {
+	id: UBig,
+	session_id: UBig,
+	options: OpenOptions,
+	length: Option<UBig>,
+	event_id: Arc<IdAllocator>
+}
+
+impl Stream {
+	pub(crate) fn new(id: UBig, session_id: UBig, options: OpenOptions, length: Option<UBig>, event_id: Arc<IdAllocator>) -> Self {
+		Self {
+			id,
+			session_id,
+			options,
+			length,
+			event_id
+		}
+	}
+
+	pub fn id(&self) -> UBig {
+		self.id.clone()
+	}
+
+	pub fn session_id(&self) -> UBig {
+		self.session_id.clone()
+	}
+
+	pub fn options(&self) -> &OpenOptions {
+		&self.options
+	}
+
+	/// Total length in bytes announced when the stream was opened.
+	pub fn length(&self) -> Option<UBig> {
+		self.length.clone()
+	}
+
+	/// A stream with a known length is a buffer transfer.
+	pub fn way(&self) -> TransWays {
+		match self.length {
+			Some(_) => TransWays::Buffer,
+			None => TransWays::Stream
+		}
+	}
+
+	// 初始进度
+	fn progress(&self) -> Progress {
+		Progress {
+			done: 0,
+			total: self.length.as_ref().and_then(|length| u64::try_from(length).ok())
+		}
+	}
+
+	// 快速生成 ID
+	fn id_set(&self, event_id: UBig) -> channel::IdSet {
+		channel::IdSet {
+			event: Some(event_id),
+			session: Some(self.session_id.clone()),
+			stream: Some(self.id.clone())
+		}
+	}
+
+	// 是否属于这个流
+	fn owns(&self, id: &channel::IdSet) -> bool {
+		id.session.as_ref() == Some(&self.session_id) && id.stream.as_ref() == Some(&self.id)
+	}
+
+	// 流或所在会话是否已经结束，以及原因
+	fn is_over(&self, data: &channel::Datagram) -> Option<LinkError> {
+		use channel::{Event as WrapEvent, link::Event as LinkEvent, stream::Event as StreamEvent};
+
+		match &data.event {
+			WrapEvent::Stream(StreamEvent::Clear(reason))
+			| WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::Clear(reason))) => match self.owns(&data.id) {
+				true => Some(reason.clone().into()),
+				false => None
+			},
+			_ => ends_session(data, &self.session_id)
+		}
+	}
+
+	// 所在会话是否已在新连接上恢复
+	fn is_resumed(&self, data: &channel::Datagram) -> bool {
+		use channel::{Event as WrapEvent, link::Event as LinkEvent, session::Event as SessionEvent};
+
+		if data.id.session.as_ref() != Some(&self.session_id) {
+			return false;
+		}
+
+		matches!(
+			&data.event,
+			WrapEvent::Session(SessionEvent::ReopenAck(Acceptable::Accept))
+			| WrapEvent::Link(LinkEvent::SessionAck(SessionEvent::ReopenAck(Acceptable::Accept)))
+		)
+	}
+
+	// 快速发送流事件
+	fn send(&self, channel: &InnerChannel, event_id: UBig, event: channel::stream::Event) {
+		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent};
+
+		let _ = channel.get_sender().send(Datagram {
+			id: self.id_set(event_id),
+			event: WrapEvent::Link(LinkEvent::StreamAck(event))
+		});
+	}
+
+	// 生成分块数据
+	fn chunk(&self, order: UBig, data: Bytes) -> channel::Datagram {
+		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, stream::Event};
+
+		Datagram {
+			id: self.id_set(self.event_id.next()),
+			event: WrapEvent::Link(LinkEvent::StreamAck(Event::Chunk(Chunk { order, data })))
+		}
+	}
+}
+
+struct WriterState {
+	// 下一个分块的序号
+	order: UBig,
+	// 尚未确认的分块，恢复连接后从第一个开始续传
+	unacked: BTreeMap<UBig, Bytes>,
+	// 已经写入的字节数
+	written: u64,
+	// 已经 Flush 过了
+	finished: bool,
+	// 尚未确认的 Flush 的事件 ID 和分块数量
+	flush: Option<(UBig, UBig)>
+}
+
+impl WriterState {
+	// 从最后连续确认的分块之后续传
+	fn resume(&self, stream: &Stream, channel: &InnerChannel) {
+		use channel::stream::{Event, Flush};
+
+		// 已确认的分块不在其中，对方已经有了
+		let sender = channel.get_sender();
+		for (order, data) in self.unacked.iter() {
+			let _ = sender.send(stream.chunk(order.clone(), data.clone()));
+		}
+
+		if let Some((event_id, length)) = &self.flush {
+			stream.send(channel, event_id.clone(), Event::Flush(Flush { length: length.clone() }));
+		}
+	}
+}
+
+/// Writable end of a stream, held by the side that opened it.
+#[derive(Clone)]
+pub struct StreamWriter {
+	stream: Stream,
+	channel: InnerChannel,
+	state: Arc<Mutex<WriterState>>,
+	progress: watch::Receiver<Progress>,
+	// 尽力模式没有确认，发送即计入进度
+	progress_sender: Arc<watch::Sender<Progress>>,
+	// 对方是否要求暂停
+	paused: watch::Receiver<bool>,
+	// 已发出但尚未确认的字节数，超过窗口就等待确认
+	in_flight: Arc<watch::Sender<usize>>,
+	send_window: usize,
+	chunk_size: usize,
+	// 同一时间只能有一次写入
+	writing: Arc<AsyncMutex<()>>,
+	requests: Arc<PendingRequests>,
+	request_timeout: Duration,
+	ended: Ended
+}
+
+impl StreamWriter {
+	pub(crate) fn new(
+		stream: Stream,
+		channel: InnerChannel,
+		context: InnerContext,
+		receiver: Inbox,
+		pending: PendingStream
+	) -> Self {
+		let state = Arc::new(Mutex::new(WriterState {
+			order: UBig::from(0u8),
+			unacked: BTreeMap::new(),
+			written: 0,
+			finished: false,
+			flush: None
+		}));
+		let (progress_sender, progress) = watch::channel(stream.progress());
+		let progress_sender = Arc::new(progress_sender);
+		let (paused_sender, paused) = watch::channel(false);
+		let in_flight = Arc::new(watch::Sender::new(0));
+		let ended = Ended::default();
+
+		context.runtime.spawn_local(writer_handler(
+			stream.clone(),
+			receiver,
+			channel.clone(),
+			context.clone(),
+			state.clone(),
+			progress_sender.clone(),
+			paused_sender,
+			in_flight.clone(),
+			ended.clone(),
+			pending
+		));
+
+		Self {
+			stream,
+			channel,
+			state,
+			progress,
+			progress_sender,
+			paused,
+			in_flight,
+			send_window: context.options.stream_send_window,
+			chunk_size: context.options.stream_chunk_size.max(1),
+			writing: Arc::new(AsyncMutex::new(())),
+			requests: context.requests.clone(),
+			request_timeout: context.options.request_timeout,
+			ended
+		}
+	}
+
+	pub fn stream(&self) -> &Stream {
+		&self.stream
+	}
+
+	/// Watch the bytes confirmed by the other party,
+	/// or just sent when `enforce_integrity` is off.
+	pub fn progress(&self) -> watch::Receiver<Progress> {
+		self.progress.clone()
+	}
+
+	/// Split `data` into ordered chunks and send them.
+	///
+	/// Suspends while the other party asked to wait with `Later`,
+	/// or while `LinkOptions::stream_send_window` bytes are still waiting for `ChunkAck`.
+	/// Fails with `Closed` once the stream has been flushed,
+	/// with `Protocol` if `data` goes beyond the announced length,
+	/// or with the reason the stream ended.
+	pub async fn write(&self, data: Bytes) -> Result<(), LinkError> {
+		// 整次写入独占，避免与其他写入交错
+		let _writing = self.writing.lock().await;
+
+		{
+			let mut state = self.state.lock().map_err(|_| LinkError::Closed)?;
+			if state.finished {
+				return Err(self.ended.get());
+			}
+
+			// 不能超过声明的长度
+			let written = state.written + data.len() as u64;
+			if let Some(total) = self.progress.borrow().total
+			&& written > total {
+				return Err(LinkError::Protocol(Reason::PROTOCOL_ERROR));
+			}
+			state.written = written;
+		}
+
+		let sender = self.channel.get_sender();
+		let mut paused = self.paused.clone();
+		let mut in_flight = self.in_flight.subscribe();
+		let chunk_size = self.chunk_size;
+		for offset in (0..data.len()).step_by(chunk_size) {
+			// 对方要求暂停时挂起
+			paused.wait_for(|paused| !*paused).await.map_err(|_| self.ended.get())?;
+
+			// 未确认的太多时等待确认，至少允许一个分块在途
+			if self.stream.options.enforce_integrity {
+				let window = self.send_window;
+				let _ = in_flight.wait_for(|bytes| *bytes == 0 || *bytes < window).await;
+			}
+
+			// 持锁发送，保证序号与发送顺序一致
+			let mut state = self.state.lock().map_err(|_| LinkError::Closed)?;
+			if state.finished {
+				return Err(self.ended.get());
+			}
+
+			let end = (offset + chunk_size).min(data.len());
+			let order = state.order.clone();
+			let chunk = data.slice(offset..end);
+			state.order += UBig::from(1u8);
+
+			// 尽力模式不保留重发缓存
+			match self.stream.options.enforce_integrity {
+				true => {
+					state.unacked.insert(order.clone(), chunk.clone());
+					self.in_flight.send_modify(|bytes| *bytes += chunk.len());
+				},
+				false => self.progress_sender.send_modify(|progress| progress.done += chunk.len() as u64)
+			}
+
+			sender.send(self.stream.chunk(order, chunk)).map_err(|_| LinkError::Closed)?;
+		}
+
+		Ok(())
+	}
+
+	/// Tell the other party that all data has been sent, and wait until it has received all of it.
+	///
+	/// `Flush` is sent again on each timeout, the other party answers with `Lack`
+	/// until the missing chunks have been sent again.
+	/// On success `progress` counts every written byte, even those whose `ChunkAck` was lost.
+	/// Fails with `Timeout` if nothing confirmed it within `LinkOptions::request_timeout`,
+	/// with the reason the stream ended if it never completed,
+	/// or with `PROTOCOL_ERROR` if less than the announced length was written.
+	pub async fn flush(&self) -> Result<(), LinkError> {
+		use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, stream::{Event, Flush}};
+
+		// 等待进行中的写入完成
+		let _writing = self.writing.lock().await;
+
+		let event_id = self.stream.event_id.next();
+		let length = {
+			let mut state = self.state.lock().map_err(|_| LinkError::Closed)?;
+			if state.finished {
+				return Err(self.ended.get());
+			}
+
+			// 必须写满声明的长度
+			if let Some(total) = self.progress.borrow().total
+			&& state.written != total {
+				return Err(LinkError::Protocol(Reason::PROTOCOL_ERROR));
+			}
+
+			state.finished = true;
+			state.flush = Some((event_id.clone(), state.order.clone()));
+			state.order.clone()
+		};
+
+		let data = Datagram {
+			id: self.stream.id_set(event_id.clone()),
+			event: WrapEvent::Link(LinkEvent::StreamAck(Event::Flush(Flush { length })))
+		};
+
+		let sender = self.channel.get_sender();
+		let mut response = self.requests.register(event_id);
+		let attempts = async {
+			loop {
+				sender.send(data.clone()).map_err(|_| LinkError::Closed)?;
+
+				let result = response.wait(FLUSH_ACK_TIMEOUT, |data| match data.event {
+					WrapEvent::Stream(Event::FlushAck) if self.stream.owns(&data.id) => Some(()),
+					_ => None
+				}).await;
+
+				match result {
+					// 流已经结束，不会再有回应
+					Err(LinkError::Timeout) if self.paused.has_changed().is_err() => return Err(self.ended.get()),
+					// 超时重发
+					Err(LinkError::Timeout) => continue,
+					result => return result
+				}
+			}
+		};
+
+		let result = timeout(self.request_timeout, attempts).await.unwrap_or(Err(LinkError::Timeout));
+
+		// 全部送达，ChunkAck 丢了的分块也算完成
+		if result.is_ok()
+		&& let Ok(mut state) = self.state.lock() {
+			let rest = std::mem::take(&mut state.unacked).into_values().map(|chunk| chunk.len() as u64).sum::<u64>();
+			self.progress_sender.send_modify(|progress| progress.done += rest);
+		}
+
+		result
+	}
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn writer_handler(
+	stream: Stream,
+	receiver: Inbox,
+	channel: InnerChannel,
+	context: InnerContext,
+	state: Arc<Mutex<WriterState>>,
+	progress: Arc<watch::Sender<Progress>>,
+	paused: watch::Sender<bool>,
+	in_flight: Arc<watch::Sender<usize>>,
+	ended: Ended,
+	// 结束时随之释放
+	_pending: PendingStream
+) {
+	use channel::{Event as WrapEvent, stream::Event as StreamEvent};
+
+	// 正在等待回应的 Reopen
+	let mut reopening = None;
+
+	while let Ok(data) = receiver.recv_async().await {
+		if let Some(error) = stream.is_over(&data) {
+			ended.set(error);
+			break;
+		}
+
+		// 会话恢复后流也要重新连上
+		if stream.is_resumed(&data) {
+			match stream.options.allow_reconnect {
+				true => {
+					let event_id = stream.event_id.next();
+					reopening = Some(event_id.clone());
+					context.runtime.spawn_local(reopen_stream(stream.clone(), channel.clone(), context.clone(), event_id));
+				},
+				// 不允许重连的流只能放弃
+				false => stream.send(&channel, stream.event_id.next(), StreamEvent::Clear(Reason::DISCONNECTED))
+			}
+			continue;
+		}
+
+		if !stream.owns(&data.id) {
+			continue;
+		}
+
+		match data.event {
+			// 对方确认收到分块
+			WrapEvent::Stream(StreamEvent::ChunkAck(ack)) => {
+				let acked = match state.lock() {
+					Ok(mut state) => state.unacked.remove(&ack.order),
+					Err(_) => None
+				};
+
+				if let Some(chunk) = acked {
+					progress.send_modify(|progress| progress.done += chunk.len() as u64);
+					in_flight.send_modify(|bytes| *bytes = bytes.saturating_sub(chunk.len()));
+				}
+			},
+			// 对方缺少部分分块，只重发这些
+			WrapEvent::Stream(StreamEvent::Lack(lack)) => {
+				let resend = match state.lock() {
+					Ok(state) => lack.orders
+						.into_iter()
+						.filter_map(|order| state.unacked.get(&order).map(|chunk| (order, chunk.clone())))
+						.collect::<Vec<_>>(),
+					Err(_) => continue
+				};
+
+				let sender = channel.get_sender();
+				for (order, chunk) in resend {
+					let _ = sender.send(stream.chunk(order, chunk));
+				}
+			},
+			// 对方同意续传，重复的回应不再续传一次
+			WrapEvent::Stream(StreamEvent::ReopenAck(Acceptable::Accept)) => {
+				if data.id.event.is_some() && data.id.event == reopening
+				&& let Ok(state) = state.lock() {
+					reopening = None;
+					state.resume(&stream, &channel);
+				}
+			},
+			WrapEvent::Stream(StreamEvent::ReopenAck(Acceptable::Reject(reason))) => {
+				ended.set(reason.into());
+				break;
+			},
+			// 对方读不过来了
+			WrapEvent::Stream(StreamEvent::Later) => {
+				paused.send_replace(true);
+			},
+			WrapEvent::Stream(StreamEvent::Go) => {
+				paused.send_replace(false);
+			},
+			// 全部送达
+			WrapEvent::Stream(StreamEvent::FlushAck) => break,
+			_ => {}
+		}
+	}
+
+	// 流已经结束，不再接受写入，也不再等待确认
+	if let Ok(mut state) = state.lock() {
+		state.finished = true;
+	}
+	in_flight.send_replace(0);
+}
+
+// 在新连接上请求续传，回应由 writer_handler 处理，这里只管重发，等不到就结束这个流
+async fn reopen_stream(stream: Stream, channel: InnerChannel, context: InnerContext, event_id: UBig) {
+	use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, stream::Event};
+
+	let data = Datagram {
+		id: stream.id_set(event_id.clone()),
+		event: WrapEvent::Link(LinkEvent::StreamAck(Event::Reopen))
+	};
+
+	let mut response = context.requests.register(event_id);
+	let result = response.resend(&channel.get_sender(), data, context.options.request_timeout, |data| match data.event {
+		WrapEvent::Stream(Event::ReopenAck(_)) => Some(()),
+		_ => None
+	}).await;
+
+	if let Err(error) = result {
+		let reason = match error {
+			LinkError::Timeout => Reason::TIMEOUT,
+			_ => Reason::DISCONNECTED
+		};
+		stream.send(&channel, stream.event_id.next(), Event::Clear(reason));
+	}
+}
+
+struct FlowState {
+	// 已收到但尚未被读取的字节数
+	buffered: usize,
+	// 是否已要求对方暂停
+	paused: bool,
+	// 暂停后仍然收到的分块数
+	since_later: usize,
+	// 已让对方继续，但还没收到新的分块
+	resuming: bool,
+	high_water: usize,
+	low_water: usize
+}
+
+/// Readable end of a stream, held by the side that accepted it.
+#[derive(Clone)]
+pub struct StreamReader {
+	stream: Stream,
+	channel: InnerChannel,
+	chunks: flume::Receiver<Chunk>,
+	progress: watch::Receiver<Progress>,
+	flow: Arc<Mutex<FlowState>>,
+	lost: Arc<Mutex<Vec<UBig>>>,
+	ended: Ended
+}
+
+impl StreamReader {
+	pub(crate) fn new(
+		stream: Stream,
+		channel: InnerChannel,
+		context: InnerContext,
+		receiver: Inbox,
+		pending: PendingStream
+	) -> Self {
+		let (chunk_sender, chunks) = flume::unbounded::<Chunk>();
+		let (progress_sender, progress) = watch::channel(stream.progress());
+		let flow = Arc::new(Mutex::new(FlowState {
+			buffered: 0,
+			paused: false,
+			since_later: 0,
+			resuming: false,
+			high_water: context.options.stream_high_water,
+			low_water: context.options.stream_low_water
+		}));
+		let lost = Arc::new(Mutex::new(Vec::new()));
+		let ended = Ended::default();
+
+		context.runtime.spawn_local(reader_handler(
+			stream.clone(),
+			receiver,
+			channel.clone(),
+			chunk_sender,
+			progress_sender,
+			flow.clone(),
+			lost.clone(),
+			ended.clone(),
+			pending,
+			context.options.stream_reorder_limit
+		));
+
+		Self {
+			stream,
+			channel,
+			chunks,
+			progress,
+			flow,
+			lost,
+			ended
+		}
+	}
+
+	pub fn stream(&self) -> &Stream {
+		&self.stream
+	}
+
+	/// Watch the bytes received from the other party.
+	pub fn progress(&self) -> watch::Receiver<Progress> {
+		self.progress.clone()
+	}
+
+	/// Orders that never arrived when `enforce_integrity` is off,
+	/// known once `read` has returned `None`.
+	pub fn lost(&self) -> Vec<UBig> {
+		self.lost.lock().map(|lost| lost.clone()).unwrap_or_default()
+	}
+
+	/// Wait for the next chunk.
+	///
+	/// With `enforce_orderliness` chunks come strictly by `order`,
+	/// otherwise as soon as they arrive.
+	/// Returns `None` once everything announced by `Flush` has been read,
+	/// fails with the reason the stream ended if it never completed.
+	pub async fn read(&self) -> Result<Option<Chunk>, LinkError> {
+		let chunk = match self.chunks.recv_async().await {
+			Ok(chunk) => chunk,
+			Err(_) => return match self.ended.peek() {
+				Some(error) => Err(error),
+				None => Ok(None)
+			}
+		};
+
+		// 读空到低水位后让对方继续
+		let resume = match self.flow.lock() {
+			Ok(mut flow) => {
+				flow.buffered = flow.buffered.saturating_sub(chunk.data.len());
+				let resume = flow.paused && flow.buffered <= flow.low_water;
+				if resume {
+					flow.paused = false;
+					flow.resuming = true;
+				}
+				resume
+			},
+			Err(_) => false
+		};
+
+		if resume {
+			self.stream.send(&self.channel, self.stream.event_id.next(), channel::stream::Event::Go);
+		}
+
+		Ok(Some(chunk))
+	}
+
+	/// Read the whole stream into one buffer, preallocated from the announced length.
+	///
+	/// Chunks lost on a best-effort stream are skipped,
+	/// otherwise fails with `Protocol` if the data does not add up to the announced length.
+	pub async fn read_to_end(&self) -> Result<Bytes, LinkError> {
+		let capacity = self.progress.borrow().total
+			.and_then(|total| usize::try_from(total).ok())
+			.unwrap_or(0)
+			.min(PREALLOCATE_LIMIT);
+		let mut buffer = BytesMut::with_capacity(capacity);
+		let mut next = UBig::from(0u8);
+		let mut pending = BTreeMap::new();
+
+		while let Some(chunk) = self.read().await? {
+			pending.insert(chunk.order, chunk.data);
+
+			// 按序拼接
+			while let Some(data) = pending.remove(&next) {
+				buffer.extend_from_slice(&data);
+				next += UBig::from(1u8);
+			}
+		}
+
+		// 尽力模式跳过丢失的分块
+		if !self.stream.options.enforce_integrity {
+			pending.into_values().for_each(|data| buffer.extend_from_slice(&data));
+			return Ok(buffer.freeze());
+		}
+
+		if !pending.is_empty() {
+			return Err(LinkError::Protocol(Reason::PROTOCOL_ERROR));
+		}
+
+		if let Some(total) = self.progress.borrow().total
+		&& buffer.len() as u64 != total {
+			return Err(LinkError::Protocol(Reason::PROTOCOL_ERROR));
+		}
+
+		Ok(buffer.freeze())
+	}
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn reader_handler(
+	stream: Stream,
+	receiver: Inbox,
+	channel: InnerChannel,
+	chunks: flume::Sender<Chunk>,
+	progress: watch::Sender<Progress>,
+	flow: Arc<Mutex<FlowState>>,
+	lost: Arc<Mutex<Vec<UBig>>>,
+	ended: Ended,
+	pending: PendingStream,
+	reorder_limit: usize
+) {
+	use channel::{Datagram, Event as WrapEvent, link::Event as LinkEvent, stream::{ChunkAck, Event as StreamEvent, Lack}};
+
+	let sender = channel.get_sender();
+	let mut received = BTreeSet::new();
+	// 从 0 开始连续收到的分块数量
+	let mut contiguous = UBig::from(0u8);
+	// Flush 的事件 ID 和声明的分块数量
+	let mut flush: Option<(UBig, UBig)> = None;
+	let mut complete = false;
+	// 有序模式下等待交付的下一个序号，以及提前到达的分块
+	let mut next = UBig::from(0u8);
+	let mut held = BTreeMap::<UBig, Chunk>::new();
+	let mut held_bytes = 0usize;
+	let ordered = stream.options.enforce_orderliness;
+	let integrity = stream.options.enforce_integrity;
+
+	// 交给应用，积压超过高水位就让对方暂停
+	let deliver = |chunk: Chunk| {
+		let pause = match flow.lock() {
+			Ok(mut flow) => {
+				flow.buffered += chunk.data.len();
+				flow.resuming = false;
+				if !flow.paused && flow.buffered >= flow.high_water {
+					flow.paused = true;
+					flow.since_later = 0;
+					true
+				} else if flow.paused {
+					// 对方迟迟不停，Later 可能丢了
+					flow.since_later += 1;
+					flow.since_later % LATER_RESEND_CHUNKS == 0
+				} else {
+					false
+				}
+			},
+			Err(_) => false
+		};
+
+		if pause {
+			stream.send(&channel, stream.event_id.next(), StreamEvent::Later);
+		}
+
+		progress.send_modify(|progress| progress.done += chunk.data.len() as u64);
+		let _ = chunks.send(chunk);
+	};
+
+	// 有序且完整的流缺了分块会卡住交付，不等 Flush，每个周期至多要一次
+	let recover = ordered && integrity;
+	let mut tick = interval(RESEND_INTERVAL);
+	let mut lack_sent = false;
+	// 见过的最大序号的下一个，包括缓存已满而丢弃的
+	let mut seen = UBig::from(0u8);
+	let request_lack = |received: &BTreeSet<UBig>, next: &UBig, seen: &UBig| {
+		let missing = missing_orders(received, next, seen, LACK_LIMIT);
+		if !missing.is_empty() {
+			stream.send(&channel, stream.event_id.next(), StreamEvent::Lack(Lack { orders: missing }));
+		}
+	};
+
+	loop {
+		let data = select! {
+			result = receiver.recv_async() => match result {
+				Ok(data) => data,
+				Err(_) => break
+			},
+			_ = tick.tick() => {
+				// 让对方继续后迟迟没有新的分块，Go 可能丢了
+				if flow.lock().is_ok_and(|flow| flow.resuming) {
+					stream.send(&channel, stream.event_id.next(), StreamEvent::Go);
+				}
+
+				lack_sent = recover && next < seen;
+				if lack_sent {
+					request_lack(&received, &next, &seen);
+				}
+				continue;
+			}
+		};
+
+		if let Some(error) = stream.is_over(&data) {
+			ended.set(error);
+			break;
+		}
+
+		if !stream.owns(&data.id) {
+			continue;
+		}
+
+		match data.event {
+			WrapEvent::Stream(StreamEvent::Chunk(chunk)) => {
+				let fresh = !received.contains(&chunk.order);
+				if chunk.order >= seen {
+					seen = &chunk.order + UBig::from(1u8);
+				}
+
+				// 已经跳过的分块来得太晚，直接丢弃
+				if fresh && ordered && chunk.order < next {
+					continue;
+				}
+
+				if fresh && ordered && chunk.order != next
+				&& held_bytes + chunk.data.len() > reorder_limit {
+					match integrity {
+						// 乱序缓存已满，不回应，等对方重发，同时要回卡住交付的分块
+						true => {
+							if !lack_sent {
+								request_lack(&received, &next, &seen);
+								lack_sent = true;
+							}
+							continue;
+						},
+						// 尽力模式不再等待，从最早缓存的分块继续
+						false => {
+							let first = held.keys().next().cloned().unwrap_or_else(|| chunk.order.clone());
+							next = first.min(chunk.order.clone());
+						}
+					}
+				}
+
+				// 重复的也要回应，对方可能没收到上一次的
+				if integrity {
+					let _ = sender.send(Datagram {
+						id: data.id,
+						event: WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::ChunkAck(ChunkAck { order: chunk.order.clone() })))
+					});
+				}
+
+				if !fresh {
+					continue;
+				}
+				received.insert(chunk.order.clone());
+				while received.contains(&contiguous) {
+					contiguous += UBig::from(1u8);
+				}
+
+				// 有序模式下按序号交付，否则到达即交付
+				let ready = match ordered {
+					true => {
+						held_bytes += chunk.data.len();
+						held.insert(chunk.order.clone(), chunk);
+
+						let mut ready = Vec::new();
+						while let Some(chunk) = held.remove(&next) {
+							held_bytes -= chunk.data.len();
+							next += UBig::from(1u8);
+							ready.push(chunk);
+						}
+						ready
+					},
+					false => vec![chunk]
+				};
+
+				ready.into_iter().for_each(&deliver);
+			},
+			WrapEvent::Stream(StreamEvent::Flush(the_flush)) => {
+				let Some(event_id) = data.id.event else {
+					continue;
+				};
+
+				// 尽力模式不等待缺少的分块，交付剩下的并记下丢失的
+				if !integrity {
+					std::mem::take(&mut held).into_values().for_each(&deliver);
+					if let Ok(mut lost) = lost.lock() {
+						*lost = missing_orders(&received, &contiguous, &the_flush.length, usize::MAX);
+					}
+
+					let _ = sender.send(Datagram {
+						id: stream.id_set(event_id),
+						event: WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::FlushAck))
+					});
+					complete = true;
+					break;
+				}
+
+				// 列出缺少的分块，让对方重发
+				let missing = missing_orders(&received, &contiguous, &the_flush.length, LACK_LIMIT);
+				if !missing.is_empty() {
+					stream.send(&channel, event_id.clone(), StreamEvent::Lack(Lack { orders: missing }));
+				}
+
+				flush = Some((event_id, the_flush.length));
+			},
+			// 对方在新连接上要求续传
+			WrapEvent::Stream(StreamEvent::Reopen) => {
+				let Some(event_id) = data.id.event else {
+					continue;
+				};
+
+				let ack = match stream.options.allow_reconnect {
+					true => Acceptable::Accept,
+					false => Acceptable::Reject(Reason::DISCONNECTED)
+				};
+				stream.send(&channel, event_id, StreamEvent::ReopenAck(ack.clone()));
+
+				if let Acceptable::Reject(reason) = ack {
+					ended.set(reason.into());
+					break;
+				}
+				continue;
+			},
+			_ => continue
+		}
+
+		// 收到的数量与声明的一致才算完整
+		if let Some((event_id, length)) = &flush
+		&& UBig::from(received.len()) == *length {
+			let _ = sender.send(Datagram {
+				id: stream.id_set(event_id.clone()),
+				event: WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::FlushAck))
+			});
+			complete = true;
+			break;
+		}
+	}
+
+	if !complete {
+		ended.set(LinkError::Closed);
+		return;
+	}
+
+	// 不会再有新的分块
+	drop(chunks);
+	drop(pending);
+
+	// 对方可能没收到 FlushAck，重发的 Flush 仍要回应，但不会一直等下去
+	let _ = timeout(FLUSH_LINGER, async {
+		while let Ok(data) = receiver.recv_async().await {
+			if stream.is_over(&data).is_some() {
+				break;
+			}
+
+			if let WrapEvent::Stream(StreamEvent::Flush(_)) = data.event
+			&& stream.owns(&data.id) {
+				let _ = sender.send(Datagram {
+					id: data.id,
+					event: WrapEvent::Link(LinkEvent::StreamAck(StreamEvent::FlushAck))
+				});
+			}
+		}
+	}).await;
+}
+
+// 找出声明数量之内尚未收到的分块，连续收到的部分不用再看
+fn missing_orders(received: &BTreeSet<UBig>, contiguous: &UBig, length: &UBig, limit: usize) -> Vec<UBig> {
+	let mut missing = Vec::new();
+	let mut order = contiguous.clone();
+
+	while order < *length && missing.len() < limit {
+		if !received.contains(&order) {
+			missing.push(order.clone());
+		}
+		order += UBig::from(1u8);
+	}
+
+	missing
+}
\ No newline at end of file
@@ -2,6 +2,18 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use thiserror::Error;
 
+pub mod memory;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mux;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcp;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod udp;
+#[cfg(all(feature = "quic", not(target_arch = "wasm32")))]
+pub mod quic;
+#[cfg(all(feature = "websocket", not(target_arch = "wasm32")))]
+pub mod websocket;
+
 #[derive(Debug, Error, Clone, PartialEq, Eq, Hash, uniffi::Error)]
 pub enum IOError {
 	#[error("Timeout while waiting to open stream.")]
@@ -25,6 +37,21 @@ pub enum IOError {
 	Unknown { code: u32, error: String },
 }
 
+// 系统 IO 错误，带上错误码
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn io_error(error: std::io::Error) -> IOError {
+	IOError::Unknown {
+		code: error.raw_os_error().unwrap_or_default() as u32,
+		error: error.to_string()
+	}
+}
+
+// 其他库的错误，没有错误码
+#[cfg(all(any(feature = "quic", feature = "websocket"), not(target_arch = "wasm32")))]
+pub(crate) fn unknown(error: String) -> IOError {
+	IOError::Unknown { code: 0, error }
+}
+
 #[uniffi::export]
 #[async_trait]
 pub trait LinkIO: Send + Sync {
@@ -0,0 +1,496 @@
+//! Two `LinkIO`s connected through channels inside one process.
+//!
+//! Meant for tests and for wiring components together without a network,
+//! `MemoryOptions` can make the wire slow, lossy or out of order.
+use std::{future::Future, pin::Pin, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+use async_trait::async_trait;
+use derive_builder::Builder;
+use tokio_with_wasm::alias::{select, task::spawn, time::sleep};
+
+use super::{BidirectionalStream, IOError, IOStream, LinkIO, ReaderStream, WritterStream};
+
+/// Faults injected on every write.
+#[derive(Clone, Debug, Builder)]
+#[builder(default)]
+pub struct MemoryOptions {
+	/// Delay before a write reaches the other side.
+	pub latency: Duration,
+	/// Chance in `0.0..=1.0` that a write is silently dropped.
+	pub loss: f64,
+	/// Chance in `0.0..=1.0` that a write is held back and delivered after the next one.
+	pub reorder: f64,
+	/// Seed of the pseudo random source, the same seed replays the same faults.
+	pub seed: u64,
+}
+
+impl Default for MemoryOptions {
+	fn default() -> Self {
+		Self {
+			latency: Duration::ZERO,
+			loss: 0.0,
+			reorder: 0.0,
+			seed: 0x2545_F491_4F6C_DD1D
+		}
+	}
+}
+
+/// Get two connected ends of a perfect wire.
+pub fn pair() -> (MemoryIO, MemoryIO) {
+	pair_with_options(MemoryOptions::default())
+}
+
+/// Get two connected ends of a wire with the faults in `options`.
+pub fn pair_with_options(options: MemoryOptions) -> (MemoryIO, MemoryIO) {
+	let (killer, killed) = flume::bounded::<()>(0);
+	let wire = Arc::new(Wire {
+		random: Mutex::new(options.seed.max(1)),
+		options,
+		disconnected: AtomicBool::new(false),
+		killer: Mutex::new(Some(killer)),
+		killed
+	});
+
+	let (left_uni, right_uni_streams) = flume::unbounded();
+	let (left_bi, right_bi_streams) = flume::unbounded();
+	let (right_uni, left_uni_streams) = flume::unbounded();
+	let (right_bi, left_bi_streams) = flume::unbounded();
+
+	let left = MemoryIO {
+		wire: wire.clone(),
+		next_id: AtomicU64::new(0),
+		parity: 0,
+		peer_uni: left_uni,
+		peer_bi: left_bi,
+		uni_streams: left_uni_streams,
+		bi_streams: left_bi_streams
+	};
+	let right = MemoryIO {
+		wire,
+		next_id: AtomicU64::new(0),
+		parity: 1,
+		peer_uni: right_uni,
+		peer_bi: right_bi,
+		uni_streams: right_uni_streams,
+		bi_streams: right_bi_streams
+	};
+
+	(left, right)
+}
+
+// 两端共用的线路
+struct Wire {
+	options: MemoryOptions,
+	random: Mutex<u64>,
+	disconnected: AtomicBool,
+	// 丢掉发送端即可唤醒所有等待者
+	killer: Mutex<Option<flume::Sender<()>>>,
+	killed: flume::Receiver<()>
+}
+
+impl Wire {
+	// xorshift64*，只求可复现
+	fn chance(&self, probability: f64) -> bool {
+		if probability <= 0.0 {
+			return false;
+		}
+
+		let Ok(mut state) = self.random.lock() else {
+			return false;
+		};
+		*state ^= *state >> 12;
+		*state ^= *state << 25;
+		*state ^= *state >> 27;
+		let value = state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11;
+
+		(value as f64 / (1u64 << 53) as f64) < probability
+	}
+
+	fn is_disconnected(&self) -> bool {
+		self.disconnected.load(Ordering::Acquire)
+	}
+
+	fn disconnect(&self) {
+		self.disconnected.store(true, Ordering::Release);
+		if let Ok(mut killer) = self.killer.lock() {
+			killer.take();
+		}
+	}
+}
+
+// 延迟送达的一次写入，计时从写入时开始
+type Delayed = (Pin<Box<dyn Future<Output = ()> + Send>>, Vec<u8>);
+
+/// One end of an in-memory wire.
+pub struct MemoryIO {
+	wire: Arc<Wire>,
+	next_id: AtomicU64,
+	parity: u64,
+	peer_uni: flume::Sender<Arc<MemoryStream>>,
+	peer_bi: flume::Sender<Arc<MemoryStream>>,
+	uni_streams: flume::Receiver<Arc<MemoryStream>>,
+	bi_streams: flume::Receiver<Arc<MemoryStream>>
+}
+
+impl MemoryIO {
+	/// Cut the wire, both ends and all their streams fail with `Disconnected`.
+	pub fn disconnect(&self) {
+		self.wire.disconnect();
+	}
+
+	pub fn is_disconnected(&self) -> bool {
+		self.wire.is_disconnected()
+	}
+
+	// 本端打开的流 ID 与对方的奇偶不同，不会冲突
+	fn open(&self, bidirectional: bool) -> Result<(Arc<MemoryStream>, Arc<MemoryStream>), IOError> {
+		if self.wire.is_disconnected() {
+			return Err(IOError::Disconnected);
+		}
+
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed) * 2 + self.parity;
+		let (local_sender, remote_inbox) = flume::unbounded();
+		let (remote_sender, local_inbox) = flume::unbounded();
+		let (closer, closing) = flume::bounded::<()>(0);
+		let state = Arc::new(StreamState {
+			closed: AtomicBool::new(false),
+			closer: Mutex::new(Some(closer)),
+			closing
+		});
+
+		let local = MemoryStream::new(id, &self.wire, &state, Some(local_sender), bidirectional.then_some(local_inbox));
+		let remote = MemoryStream::new(id, &self.wire, &state, bidirectional.then_some(remote_sender), Some(remote_inbox));
+		Ok((local, remote))
+	}
+
+	async fn accept(&self, streams: &flume::Receiver<Arc<MemoryStream>>) -> Result<Arc<MemoryStream>, IOError> {
+		if self.wire.is_disconnected() {
+			return Err(IOError::Disconnected);
+		}
+
+		select! {
+			result = streams.recv_async() => result.map_err(|_| IOError::Disconnected),
+			_ = self.wire.killed.recv_async() => Err(IOError::Disconnected)
+		}
+	}
+}
+
+// 任何一端不再使用，线路就断了
+impl Drop for MemoryIO {
+	fn drop(&mut self) {
+		self.wire.disconnect();
+	}
+}
+
+#[async_trait]
+impl LinkIO for MemoryIO {
+	async fn open_uni_stream(&self) -> Result<Arc<dyn WritterStream>, IOError> {
+		let (local, remote) = self.open(false)?;
+		self.peer_uni.send(remote).map_err(|_| IOError::Disconnected)?;
+		Ok(local)
+	}
+
+	async fn open_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		let (local, remote) = self.open(true)?;
+		self.peer_bi.send(remote).map_err(|_| IOError::Disconnected)?;
+		Ok(local)
+	}
+
+	async fn accept_uni_stream(&self) -> Result<Arc<dyn ReaderStream>, IOError> {
+		let stream = self.accept(&self.uni_streams).await?;
+		Ok(stream)
+	}
+
+	async fn accept_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		let stream = self.accept(&self.bi_streams).await?;
+		Ok(stream)
+	}
+}
+
+// 流的两端共用的关闭状态
+struct StreamState {
+	closed: AtomicBool,
+	closer: Mutex<Option<flume::Sender<()>>>,
+	closing: flume::Receiver<()>
+}
+
+/// One end of a stream on a `MemoryIO`.
+pub struct MemoryStream {
+	id: u64,
+	wire: Arc<Wire>,
+	state: Arc<StreamState>,
+	outgoing: Option<flume::Sender<Vec<u8>>>,
+	// 有延迟时按写入顺序排队，由一个任务依次送出
+	delayed: Option<flume::Sender<Delayed>>,
+	incoming: Option<flume::Receiver<Vec<u8>>>,
+	// 为乱序而扣下的一次写入
+	held: Mutex<Option<Vec<u8>>>
+}
+
+impl MemoryStream {
+	fn new(
+		id: u64,
+		wire: &Arc<Wire>,
+		state: &Arc<StreamState>,
+		outgoing: Option<flume::Sender<Vec<u8>>>,
+		incoming: Option<flume::Receiver<Vec<u8>>>
+	) -> Arc<Self> {
+		let delayed = match (&outgoing, wire.options.latency.is_zero()) {
+			(Some(outgoing), false) => {
+				let (queue, queued) = flume::unbounded::<Delayed>();
+				let outgoing = outgoing.clone();
+				spawn(async move {
+					// 各自的时限已经在走，这里只保证先写先到
+					while let Ok((due, buffer)) = queued.recv_async().await {
+						due.await;
+						let _ = outgoing.send(buffer);
+					}
+				});
+				Some(queue)
+			},
+			_ => None
+		};
+
+		Arc::new(Self {
+			id,
+			wire: wire.clone(),
+			state: state.clone(),
+			outgoing,
+			delayed,
+			incoming,
+			held: Mutex::new(None)
+		})
+	}
+
+	// 按延迟交给对方
+	fn deliver(&self, buffer: Vec<u8>) {
+		match (&self.delayed, &self.outgoing) {
+			(Some(delayed), _) => {
+				let _ = delayed.send((Box::pin(sleep(self.wire.options.latency)), buffer));
+			},
+			(None, Some(outgoing)) => {
+				let _ = outgoing.send(buffer);
+			},
+			(None, None) => {}
+		}
+	}
+
+	fn is_over(&self) -> Option<IOError> {
+		if self.wire.is_disconnected() {
+			return Some(IOError::Disconnected);
+		}
+
+		if self.state.closed.load(Ordering::Acquire) {
+			return Some(IOError::ClosedStream);
+		}
+
+		None
+	}
+}
+
+#[async_trait]
+impl IOStream for MemoryStream {
+	fn link_id(&self) -> u64 {
+		self.id
+	}
+
+	async fn close(&self) -> Result<(), IOError> {
+		// 扣下的写入在关闭前送出
+		if let Ok(mut held) = self.held.lock()
+		&& let Some(buffer) = held.take() {
+			self.deliver(buffer);
+		}
+
+		self.state.closed.store(true, Ordering::Release);
+		if let Ok(mut closer) = self.state.closer.lock() {
+			closer.take();
+		}
+
+		Ok(())
+	}
+
+	async fn is_closed(&self) -> bool {
+		self.is_over().is_some()
+	}
+}
+
+#[async_trait]
+impl ReaderStream for MemoryStream {
+	async fn read(&self) -> Result<Vec<u8>, IOError> {
+		let Some(incoming) = &self.incoming else {
+			return Err(IOError::ReadError);
+		};
+
+		loop {
+			// 关闭前送达的数据仍然可以读完
+			if let Ok(buffer) = incoming.try_recv() {
+				return Ok(buffer);
+			}
+
+			if let Some(error) = self.is_over() {
+				return Err(error);
+			}
+
+			select! {
+				result = incoming.recv_async() => return result.map_err(|_| IOError::ClosedStream),
+				_ = self.state.closing.recv_async() => continue,
+				_ = self.wire.killed.recv_async() => continue
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl WritterStream for MemoryStream {
+	async fn write(&self, buffer: &Vec<u8>) -> Result<(), IOError> {
+		if self.outgoing.is_none() {
+			return Err(IOError::WriteError);
+		}
+
+		if let Some(error) = self.is_over() {
+			return Err(error);
+		}
+
+		// 丢了也算写成功，和真实网络一样
+		if self.wire.chance(self.wire.options.loss) {
+			return Ok(());
+		}
+
+		let Ok(mut held) = self.held.lock() else {
+			return Err(IOError::WriteError);
+		};
+
+		// 扣下这一次，等下一次写入后再送出
+		if held.is_none() && self.wire.chance(self.wire.options.reorder) {
+			*held = Some(buffer.clone());
+			return Ok(());
+		}
+
+		self.deliver(buffer.clone());
+		if let Some(previous) = held.take() {
+			self.deliver(previous);
+		}
+
+		Ok(())
+	}
+}
+
+impl BidirectionalStream for MemoryStream {}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::Arc, time::Duration};
+	use tokio::time::{sleep, timeout};
+
+	use super::*;
+
+	async fn open(left: &MemoryIO, right: &MemoryIO) -> (Arc<dyn WritterStream>, Arc<dyn ReaderStream>) {
+		let writter = left.open_uni_stream().await.unwrap();
+		let reader = right.accept_uni_stream().await.unwrap();
+		(writter, reader)
+	}
+
+	// 写完后关闭，读出送达的全部内容
+	async fn send_all(options: MemoryOptions, count: u8) -> Vec<u8> {
+		let (left, right) = pair_with_options(options);
+		let (writter, reader) = open(&left, &right).await;
+
+		for value in 0..count {
+			writter.write(&vec![value]).await.unwrap();
+		}
+		writter.close().await.unwrap();
+
+		let mut received = vec![];
+		while let Ok(buffer) = reader.read().await {
+			received.extend(buffer);
+		}
+		received
+	}
+
+	#[tokio::test]
+	async fn round_trip() {
+		let (left, right) = pair();
+
+		let (writter, reader) = open(&left, &right).await;
+		writter.write(&b"ping".to_vec()).await.unwrap();
+		assert_eq!(reader.read().await.unwrap(), b"ping");
+
+		let local = right.open_bi_stream().await.unwrap();
+		let remote = left.accept_bi_stream().await.unwrap();
+		local.write(&b"ping".to_vec()).await.unwrap();
+		assert_eq!(remote.read().await.unwrap(), b"ping");
+		remote.write(&b"pong".to_vec()).await.unwrap();
+		assert_eq!(local.read().await.unwrap(), b"pong");
+	}
+
+	#[tokio::test]
+	async fn loss() {
+		let all = MemoryOptionsBuilder::default().loss(1.0).build().unwrap();
+		assert!(send_all(all, 100).await.is_empty());
+
+		let half = MemoryOptionsBuilder::default().loss(0.5).build().unwrap();
+		let received = send_all(half.clone(), 200).await;
+		assert!(!received.is_empty() && received.len() < 200);
+		// 送达的仍然保持顺序
+		assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+		// 同一个种子丢同样的包
+		assert_eq!(send_all(half, 200).await, received);
+	}
+
+	#[tokio::test]
+	async fn reorder() {
+		let always = MemoryOptionsBuilder::default().reorder(1.0).build().unwrap();
+		// 每次扣下一个，和下一个交换位置，最后一个在关闭时送出
+		assert_eq!(send_all(always, 5).await, vec![1, 0, 3, 2, 4]);
+
+		let never = MemoryOptions::default();
+		assert_eq!(send_all(never, 5).await, vec![0, 1, 2, 3, 4]);
+	}
+
+	#[tokio::test]
+	async fn latency() {
+		let options = MemoryOptionsBuilder::default().latency(Duration::from_millis(50)).build().unwrap();
+		let (left, right) = pair_with_options(options);
+		let (writter, reader) = open(&left, &right).await;
+
+		writter.write(&b"late".to_vec()).await.unwrap();
+		assert!(timeout(Duration::from_millis(10), reader.read()).await.is_err());
+		assert_eq!(reader.read().await.unwrap(), b"late");
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn latency_keeps_order() {
+		let options = MemoryOptionsBuilder::default().latency(Duration::from_millis(5)).build().unwrap();
+		let (left, right) = pair_with_options(options);
+		let (writter, reader) = open(&left, &right).await;
+
+		for index in 0..200u8 {
+			writter.write(&vec![index]).await.unwrap();
+		}
+		for index in 0..200u8 {
+			assert_eq!(reader.read().await.unwrap(), vec![index]);
+		}
+	}
+
+	#[tokio::test]
+	async fn disconnect() {
+		let (left, right) = pair();
+		let (writter, reader) = open(&left, &right).await;
+
+		// 等待中的读取也会被唤醒
+		let (read, _) = tokio::join!(reader.read(), async {
+			sleep(Duration::from_millis(10)).await;
+			left.disconnect();
+		});
+		assert_eq!(read, Err(IOError::Disconnected));
+
+		assert!(right.is_disconnected());
+		assert_eq!(writter.write(&b"gone".to_vec()).await, Err(IOError::Disconnected));
+		assert!(left.open_uni_stream().await.is_err());
+		assert!(right.accept_uni_stream().await.is_err());
+
+		// 一端被丢弃也算断开
+		let (left, right) = pair();
+		drop(left);
+		assert!(right.is_disconnected());
+	}
+}
\ No newline at end of file
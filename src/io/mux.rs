@@ -0,0 +1,316 @@
+//! Virtual streams over a transport that only carries one sequence of frames.
+//!
+//! Every frame is `kind: u8`, `stream id: u64` (big endian) and the payload,
+//! the carrier is responsible for keeping frames apart. A `DATA` frame is one `write`
+//! and comes out as one `read`.
+use std::sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use tokio::{select, sync::{mpsc, Notify}};
+
+use super::{BidirectionalStream, IOError, IOStream, LinkIO, ReaderStream, WritterStream};
+
+const FRAME_OPEN_UNI: u8 = 0;
+const FRAME_OPEN_BI: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+
+/// Bytes in front of the payload of every frame.
+pub const HEADER_SIZE: usize = 1 + 8;
+
+/// Largest payload of a single frame.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Which end of the carrier this is, decides the parity of stream ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MuxSide {
+	Client,
+	Server
+}
+
+// 收到的数据交给对应的虚拟流
+struct Route {
+	data: Option<flume::Sender<Vec<u8>>>,
+	closed: Arc<AtomicBool>
+}
+
+struct Shared {
+	frames: mpsc::UnboundedSender<Vec<u8>>,
+	routes: DashMap<u64, Route>,
+	disconnected: AtomicBool,
+	stopped: Notify
+}
+
+impl Shared {
+	fn send(&self, kind: u8, stream_id: u64, payload: &[u8]) -> Result<(), IOError> {
+		if self.disconnected.load(Ordering::Acquire) {
+			return Err(IOError::Disconnected);
+		}
+
+		if payload.len() > MAX_FRAME_SIZE {
+			return Err(IOError::WriteError);
+		}
+
+		let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+		frame.push(kind);
+		frame.extend_from_slice(&stream_id.to_be_bytes());
+		frame.extend_from_slice(payload);
+
+		self.frames.send(frame).map_err(|_| IOError::Disconnected)
+	}
+
+	// 承载断了，所有流都随之结束
+	fn disconnect(&self) {
+		self.disconnected.store(true, Ordering::Release);
+		self.stopped.notify_one();
+		for entry in self.routes.iter() {
+			entry.value().closed.store(true, Ordering::Release);
+		}
+		self.routes.clear();
+	}
+}
+
+/// `LinkIO` whose streams are multiplexed over one carrier.
+pub struct MuxIO {
+	shared: Arc<Shared>,
+	next_id: AtomicU64,
+	parity: u64,
+	uni_streams: flume::Receiver<Arc<MuxStream>>,
+	bi_streams: flume::Receiver<Arc<MuxStream>>
+}
+
+impl MuxIO {
+	/// Get the `LinkIO` along with the two halves the carrier drives.
+	pub(crate) fn new(side: MuxSide) -> (Self, MuxIncoming, MuxOutgoing) {
+		let (frames, frame_receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+		let (uni_sender, uni_streams) = flume::unbounded();
+		let (bi_sender, bi_streams) = flume::unbounded();
+
+		let shared = Arc::new(Shared {
+			frames,
+			routes: DashMap::new(),
+			disconnected: AtomicBool::new(false),
+			stopped: Notify::new()
+		});
+
+		let io = Self {
+			shared: shared.clone(),
+			next_id: AtomicU64::new(0),
+			parity: match side {
+				MuxSide::Client => 0,
+				MuxSide::Server => 1
+			},
+			uni_streams,
+			bi_streams
+		};
+		let incoming = MuxIncoming {
+			shared: shared.clone(),
+			uni_streams: uni_sender,
+			bi_streams: bi_sender
+		};
+		let outgoing = MuxOutgoing {
+			frames: frame_receiver,
+			shared,
+			stopped: false
+		};
+
+		(io, incoming, outgoing)
+	}
+
+	/// Whether the carrier has been lost.
+	pub fn is_disconnected(&self) -> bool {
+		self.shared.disconnected.load(Ordering::Acquire)
+	}
+
+	// 本端打开的流 ID 与对方的奇偶不同，不会冲突
+	fn open(&self, kind: u8, readable: bool) -> Result<Arc<MuxStream>, IOError> {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed) * 2 + self.parity;
+		let stream = MuxStream::register(id, &self.shared, readable);
+		self.shared.send(kind, id, &[])?;
+		Ok(stream)
+	}
+}
+
+// 不再使用就断开，对方会随之收到断开
+impl Drop for MuxIO {
+	fn drop(&mut self) {
+		self.shared.disconnect();
+	}
+}
+
+#[async_trait]
+impl LinkIO for MuxIO {
+	async fn open_uni_stream(&self) -> Result<Arc<dyn WritterStream>, IOError> {
+		Ok(self.open(FRAME_OPEN_UNI, false)?)
+	}
+
+	async fn open_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		Ok(self.open(FRAME_OPEN_BI, true)?)
+	}
+
+	async fn accept_uni_stream(&self) -> Result<Arc<dyn ReaderStream>, IOError> {
+		let stream = self.uni_streams.recv_async().await.map_err(|_| IOError::Disconnected)?;
+		Ok(stream)
+	}
+
+	async fn accept_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		let stream = self.bi_streams.recv_async().await.map_err(|_| IOError::Disconnected)?;
+		Ok(stream)
+	}
+}
+
+/// Hands the frames read by the carrier to the streams of a `MuxIO`.
+pub(crate) struct MuxIncoming {
+	shared: Arc<Shared>,
+	uni_streams: flume::Sender<Arc<MuxStream>>,
+	bi_streams: flume::Sender<Arc<MuxStream>>
+}
+
+impl MuxIncoming {
+	/// Handle one frame, `false` means it is malformed and the carrier should be dropped.
+	pub fn handle(&self, frame: &[u8]) -> bool {
+		if frame.len() < HEADER_SIZE || frame.len() > HEADER_SIZE + MAX_FRAME_SIZE {
+			return false;
+		}
+
+		let kind = frame[0];
+		let mut id = [0u8; 8];
+		id.copy_from_slice(&frame[1..HEADER_SIZE]);
+		let id = u64::from_be_bytes(id);
+		let payload = &frame[HEADER_SIZE..];
+
+		match kind {
+			FRAME_OPEN_UNI => {
+				let stream = MuxStream::register(id, &self.shared, true);
+				let _ = self.uni_streams.send(stream);
+			},
+			FRAME_OPEN_BI => {
+				let stream = MuxStream::register(id, &self.shared, true);
+				let _ = self.bi_streams.send(stream);
+			},
+			FRAME_DATA => {
+				let sender = self.shared.routes.get(&id).and_then(|route| route.data.clone());
+				if let Some(sender) = sender {
+					let _ = sender.send(payload.to_vec());
+				}
+			},
+			FRAME_CLOSE => {
+				if let Some((_, route)) = self.shared.routes.remove(&id) {
+					route.closed.store(true, Ordering::Release);
+				}
+			},
+			_ => return false
+		}
+
+		true
+	}
+
+	/// The carrier can no longer read.
+	pub fn disconnect(&self) {
+		self.shared.disconnect();
+	}
+}
+
+/// Frames written by the streams of a `MuxIO`, for the carrier to send.
+pub(crate) struct MuxOutgoing {
+	frames: mpsc::UnboundedReceiver<Vec<u8>>,
+	shared: Arc<Shared>,
+	stopped: bool
+}
+
+impl MuxOutgoing {
+	/// Next frame to send, `None` once disconnected and the queued frames are sent.
+	pub async fn next(&mut self) -> Option<Vec<u8>> {
+		if !self.stopped {
+			select! {
+				frame = self.frames.recv() => return frame,
+				_ = self.shared.stopped.notified() => self.stopped = true
+			}
+		}
+
+		// 已经排队的帧（比如 CLOSE）仍然发出去
+		self.frames.try_recv().ok()
+	}
+
+	/// The carrier can no longer write.
+	pub fn disconnect(&self) {
+		self.shared.disconnect();
+	}
+}
+
+/// One virtual stream inside a `MuxIO`.
+pub struct MuxStream {
+	id: u64,
+	shared: Arc<Shared>,
+	inbox: flume::Receiver<Vec<u8>>,
+	closed: Arc<AtomicBool>
+}
+
+impl MuxStream {
+	// 登记路由，只写的流收不到数据
+	fn register(id: u64, shared: &Arc<Shared>, readable: bool) -> Arc<Self> {
+		let (sender, inbox) = flume::unbounded();
+		let closed = Arc::new(AtomicBool::new(false));
+
+		shared.routes.insert(id, Route {
+			data: readable.then_some(sender),
+			closed: closed.clone()
+		});
+
+		Arc::new(Self {
+			id,
+			shared: shared.clone(),
+			inbox,
+			closed
+		})
+	}
+}
+
+#[async_trait]
+impl IOStream for MuxStream {
+	fn link_id(&self) -> u64 {
+		self.id
+	}
+
+	async fn close(&self) -> Result<(), IOError> {
+		if self.closed.swap(true, Ordering::AcqRel) {
+			return Ok(());
+		}
+
+		self.shared.routes.remove(&self.id);
+		match self.shared.send(FRAME_CLOSE, self.id, &[]) {
+			// 承载已经断了，流也就关了
+			Err(IOError::Disconnected) => Ok(()),
+			result => result
+		}
+	}
+
+	async fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::Acquire)
+	}
+}
+
+#[async_trait]
+impl ReaderStream for MuxStream {
+	async fn read(&self) -> Result<Vec<u8>, IOError> {
+		// 关闭前收到的数据仍然可以读完
+		match self.inbox.recv_async().await {
+			Ok(buffer) => Ok(buffer),
+			Err(_) if self.shared.disconnected.load(Ordering::Acquire) => Err(IOError::Disconnected),
+			Err(_) => Err(IOError::ClosedStream)
+		}
+	}
+}
+
+#[async_trait]
+impl WritterStream for MuxStream {
+	async fn write(&self, buffer: &Vec<u8>) -> Result<(), IOError> {
+		if self.closed.load(Ordering::Acquire) {
+			return Err(IOError::ClosedStream);
+		}
+
+		self.shared.send(FRAME_DATA, self.id, buffer)
+	}
+}
+
+impl BidirectionalStream for MuxStream {}
\ No newline at end of file
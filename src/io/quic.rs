@@ -0,0 +1,304 @@
+//! QUIC transport on quinn, each `LinkIO` stream is one QUIC stream.
+//!
+//! QUIC streams are byte streams, so every `write` is sent with a `u32` length (big endian)
+//! in front and comes out as one `read`.
+use std::{net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use async_trait::async_trait;
+use quinn::{
+	ClientConfig, Connection, ConnectionError, Endpoint, ReadError, ReadExactError, RecvStream, SendStream, ServerConfig, VarInt, WriteError
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::{io_error, unknown, BidirectionalStream, IOError, IOStream, LinkIO, ReaderStream, WritterStream};
+
+/// Largest payload of a single write.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// `LinkIO` over one QUIC connection.
+pub struct QuicIO {
+	connection: Connection,
+	// 客户端自己建立的 endpoint，要和连接一样长寿
+	_endpoint: Option<Endpoint>
+}
+
+impl QuicIO {
+	/// Connect to `addr` from a fresh endpoint, `server_name` must match the certificate.
+	pub async fn connect(addr: SocketAddr, server_name: &str, config: ClientConfig) -> Result<Self, IOError> {
+		let bind: SocketAddr = match addr {
+			SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+			SocketAddr::V6(_) => ([0u16; 8], 0).into()
+		};
+
+		let endpoint = Endpoint::client(bind).map_err(io_error)?;
+		let connecting = endpoint
+			.connect_with(config, addr, server_name)
+			.map_err(|error| unknown(error.to_string()))?;
+		let connection = connecting.await.map_err(connection_error)?;
+
+		Ok(Self {
+			connection,
+			_endpoint: Some(endpoint)
+		})
+	}
+
+	/// Wrap an established connection, such as one accepted from a shared endpoint.
+	pub fn from_connection(connection: Connection) -> Self {
+		Self {
+			connection,
+			_endpoint: None
+		}
+	}
+
+	pub fn remote_address(&self) -> SocketAddr {
+		self.connection.remote_address()
+	}
+
+	/// Close the whole connection, all streams fail with `Disconnected`.
+	pub fn close(&self) {
+		self.connection.close(VarInt::from_u32(0), b"");
+	}
+}
+
+#[async_trait]
+impl LinkIO for QuicIO {
+	async fn open_uni_stream(&self) -> Result<Arc<dyn WritterStream>, IOError> {
+		let send = self.connection.open_uni().await.map_err(connection_error)?;
+		Ok(QuicStream::new(Some(send), None))
+	}
+
+	async fn open_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		let (send, recv) = self.connection.open_bi().await.map_err(connection_error)?;
+		Ok(QuicStream::new(Some(send), Some(recv)))
+	}
+
+	async fn accept_uni_stream(&self) -> Result<Arc<dyn ReaderStream>, IOError> {
+		let recv = self.connection.accept_uni().await.map_err(connection_error)?;
+		Ok(QuicStream::new(None, Some(recv)))
+	}
+
+	async fn accept_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		let (send, recv) = self.connection.accept_bi().await.map_err(connection_error)?;
+		Ok(QuicStream::new(Some(send), Some(recv)))
+	}
+}
+
+/// A server endpoint that wraps each incoming connection as a `QuicIO`.
+pub struct QuicIOListener {
+	endpoint: Endpoint
+}
+
+impl QuicIOListener {
+	pub fn bind(addr: SocketAddr, config: ServerConfig) -> Result<Self, IOError> {
+		let endpoint = Endpoint::server(config, addr).map_err(io_error)?;
+		Ok(Self { endpoint })
+	}
+
+	pub fn local_addr(&self) -> Result<SocketAddr, IOError> {
+		self.endpoint.local_addr().map_err(io_error)
+	}
+
+	/// Wait for the next client to finish its handshake.
+	pub async fn accept(&self) -> Result<QuicIO, IOError> {
+		loop {
+			let incoming = self.endpoint.accept().await.ok_or(IOError::Disconnected)?;
+
+			// 握手失败的客户端不影响监听
+			if let Ok(connection) = incoming.await {
+				return Ok(QuicIO::from_connection(connection));
+			}
+		}
+	}
+}
+
+/// One QUIC stream, either half is missing on a unidirectional stream.
+pub struct QuicStream {
+	id: u64,
+	send: Option<AsyncMutex<SendStream>>,
+	recv: Option<AsyncMutex<RecvStream>>,
+	closed: AtomicBool
+}
+
+impl QuicStream {
+	fn new(send: Option<SendStream>, recv: Option<RecvStream>) -> Arc<Self> {
+		let id = match (&send, &recv) {
+			(Some(send), _) => u64::from(send.id()),
+			(None, Some(recv)) => u64::from(recv.id()),
+			(None, None) => 0
+		};
+
+		Arc::new(Self {
+			id,
+			send: send.map(AsyncMutex::new),
+			recv: recv.map(AsyncMutex::new),
+			closed: AtomicBool::new(false)
+		})
+	}
+}
+
+#[async_trait]
+impl IOStream for QuicStream {
+	fn link_id(&self) -> u64 {
+		self.id
+	}
+
+	async fn close(&self) -> Result<(), IOError> {
+		if self.closed.swap(true, Ordering::AcqRel) {
+			return Ok(());
+		}
+
+		// 已写入的数据仍会送达
+		if let Some(send) = &self.send {
+			let _ = send.lock().await.finish();
+		}
+
+		// 正在读取时不能等锁，读取会随对方结束而返回
+		if let Some(recv) = &self.recv
+		&& let Ok(mut recv) = recv.try_lock() {
+			let _ = recv.stop(VarInt::from_u32(0));
+		}
+
+		Ok(())
+	}
+
+	async fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::Acquire)
+	}
+}
+
+#[async_trait]
+impl ReaderStream for QuicStream {
+	async fn read(&self) -> Result<Vec<u8>, IOError> {
+		let Some(recv) = &self.recv else {
+			return Err(IOError::ReadError);
+		};
+
+		if self.closed.load(Ordering::Acquire) {
+			return Err(IOError::ClosedStream);
+		}
+
+		let mut recv = recv.lock().await;
+		let mut header = [0u8; 4];
+		recv.read_exact(&mut header).await.map_err(read_exact_error)?;
+
+		let length = u32::from_be_bytes(header) as usize;
+		if length > MAX_FRAME_SIZE {
+			return Err(IOError::ReadError);
+		}
+
+		let mut buffer = vec![0u8; length];
+		recv.read_exact(&mut buffer).await.map_err(read_exact_error)?;
+
+		Ok(buffer)
+	}
+}
+
+#[async_trait]
+impl WritterStream for QuicStream {
+	async fn write(&self, buffer: &Vec<u8>) -> Result<(), IOError> {
+		let Some(send) = &self.send else {
+			return Err(IOError::WriteError);
+		};
+
+		if self.closed.load(Ordering::Acquire) {
+			return Err(IOError::ClosedStream);
+		}
+
+		if buffer.len() > MAX_FRAME_SIZE {
+			return Err(IOError::WriteError);
+		}
+
+		// 长度和内容一起写，避免并发写入交错
+		let mut frame = Vec::with_capacity(4 + buffer.len());
+		frame.extend_from_slice(&(buffer.len() as u32).to_be_bytes());
+		frame.extend_from_slice(buffer);
+
+		send.lock().await.write_all(&frame).await.map_err(write_error)
+	}
+}
+
+impl BidirectionalStream for QuicStream {}
+
+/// Generate a self-signed certificate for `names`, such as `["localhost"]`.
+pub fn self_signed(names: Vec<String>) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), IOError> {
+	let certified = rcgen::generate_simple_self_signed(names).map_err(|error| unknown(error.to_string()))?;
+	let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+	Ok((certified.cert.der().clone(), key.into()))
+}
+
+/// Server config that presents `cert`.
+pub fn server_config(cert: CertificateDer<'static>, key: PrivateKeyDer<'static>) -> Result<ServerConfig, IOError> {
+	ServerConfig::with_single_cert(vec![cert], key).map_err(|error| unknown(error.to_string()))
+}
+
+/// Client config that trusts only `cert`, enough for a self-signed loopback server.
+pub fn client_config(cert: CertificateDer<'static>) -> Result<ClientConfig, IOError> {
+	let mut roots = rustls::RootCertStore::empty();
+	roots.add(cert).map_err(|error| unknown(error.to_string()))?;
+
+	ClientConfig::with_root_certificates(Arc::new(roots)).map_err(|error| unknown(error.to_string()))
+}
+
+/// Matching server and client configs around a fresh self-signed `localhost` certificate.
+pub fn loopback_configs() -> Result<(ServerConfig, ClientConfig), IOError> {
+	let (cert, key) = self_signed(vec!["localhost".into()])?;
+	Ok((server_config(cert.clone(), key)?, client_config(cert)?))
+}
+
+// 连接没了，流也就没了
+fn connection_error(error: ConnectionError) -> IOError {
+	match error {
+		ConnectionError::VersionMismatch | ConnectionError::TransportError(_) => unknown(error.to_string()),
+		_ => IOError::Disconnected
+	}
+}
+
+fn write_error(error: WriteError) -> IOError {
+	match error {
+		WriteError::Stopped(_) | WriteError::ClosedStream => IOError::ClosedStream,
+		WriteError::ConnectionLost(_) => IOError::Disconnected,
+		_ => IOError::WriteError
+	}
+}
+
+fn read_exact_error(error: ReadExactError) -> IOError {
+	match error {
+		// 对方结束了流
+		ReadExactError::FinishedEarly(_) => IOError::ClosedStream,
+		ReadExactError::ReadError(ReadError::Reset(_) | ReadError::ClosedStream) => IOError::ClosedStream,
+		ReadExactError::ReadError(ReadError::ConnectionLost(_)) => IOError::Disconnected,
+		ReadExactError::ReadError(_) => IOError::ReadError
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn round_trip() {
+		let (server_config, client_config) = loopback_configs().unwrap();
+		let listener = QuicIOListener::bind(([127, 0, 0, 1], 0).into(), server_config).unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (client, server) = tokio::join!(
+			async { QuicIO::connect(addr, "localhost", client_config).await.unwrap() },
+			async { listener.accept().await.unwrap() }
+		);
+
+		let writter = client.open_uni_stream().await.unwrap();
+		writter.write(&b"ping".to_vec()).await.unwrap();
+		let reader = server.accept_uni_stream().await.unwrap();
+		assert_eq!(reader.read().await.unwrap(), b"ping");
+
+		let local = server.open_bi_stream().await.unwrap();
+		local.write(&b"ping".to_vec()).await.unwrap();
+		let remote = client.accept_bi_stream().await.unwrap();
+		assert_eq!(remote.read().await.unwrap(), b"ping");
+		remote.write(&b"pong".to_vec()).await.unwrap();
+		assert_eq!(local.read().await.unwrap(), b"pong");
+
+		client.close();
+		assert_eq!(local.read().await, Err(IOError::Disconnected));
+	}
+}
\ No newline at end of file
@@ -0,0 +1,129 @@
+//! Plain TCP transport, many virtual streams carried over one socket.
+//!
+//! Every frame of `mux` is sent with a `u32` length (big endian) in front.
+use std::net::SocketAddr;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpListener, TcpStream, ToSocketAddrs}
+};
+
+use super::{io_error, IOError};
+use super::mux::{MuxIO, MuxIncoming, MuxOutgoing, MuxSide, HEADER_SIZE, MAX_FRAME_SIZE};
+
+/// Connect to a `TcpIOListener`.
+pub async fn connect(addr: impl ToSocketAddrs) -> Result<MuxIO, IOError> {
+	let socket = TcpStream::connect(addr).await.map_err(io_error)?;
+	Ok(from_stream(socket, MuxSide::Client))
+}
+
+/// Wrap an already connected socket, the two ends must pick different sides.
+///
+/// Spawns its tasks on the current tokio runtime.
+pub fn from_stream(socket: TcpStream, side: MuxSide) -> MuxIO {
+	let _ = socket.set_nodelay(true);
+	let (read_half, write_half) = socket.into_split();
+	let (io, incoming, outgoing) = MuxIO::new(side);
+
+	tokio::spawn(write_handler(write_half, outgoing));
+	tokio::spawn(read_handler(read_half, incoming));
+
+	io
+}
+
+/// Accepts incoming sockets and wraps each one as a `MuxIO`.
+pub struct TcpIOListener {
+	listener: TcpListener
+}
+
+impl TcpIOListener {
+	pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self, IOError> {
+		let listener = TcpListener::bind(addr).await.map_err(io_error)?;
+		Ok(Self { listener })
+	}
+
+	pub fn local_addr(&self) -> Result<SocketAddr, IOError> {
+		self.listener.local_addr().map_err(io_error)
+	}
+
+	/// Wait for the next client.
+	pub async fn accept(&self) -> Result<(MuxIO, SocketAddr), IOError> {
+		let (socket, addr) = self.listener.accept().await.map_err(io_error)?;
+		Ok((from_stream(socket, MuxSide::Server), addr))
+	}
+}
+
+// 独占写入端，保证帧不会交错
+async fn write_handler(mut socket: OwnedWriteHalf, mut outgoing: MuxOutgoing) {
+	while let Some(frame) = outgoing.next().await {
+		let length = (frame.len() as u32).to_be_bytes();
+		if socket.write_all(&length).await.is_err()
+		|| socket.write_all(&frame).await.is_err() {
+			outgoing.disconnect();
+			break;
+		}
+	}
+
+	let _ = socket.shutdown().await;
+}
+
+// 按长度拆帧
+async fn read_handler(mut socket: OwnedReadHalf, incoming: MuxIncoming) {
+	let mut header = [0u8; 4];
+
+	loop {
+		if socket.read_exact(&mut header).await.is_err() {
+			break;
+		}
+
+		// 对方不守规矩，没法再对齐帧
+		let length = u32::from_be_bytes(header) as usize;
+		if length > HEADER_SIZE + MAX_FRAME_SIZE {
+			break;
+		}
+
+		let mut frame = vec![0u8; length];
+		if socket.read_exact(&mut frame).await.is_err()
+		|| !incoming.handle(&frame) {
+			break;
+		}
+	}
+
+	incoming.disconnect();
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::io::LinkIO;
+	use super::*;
+
+	#[tokio::test]
+	async fn round_trip() {
+		let listener = TcpIOListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (client, (server, _)) = tokio::join!(
+			async { connect(addr).await.unwrap() },
+			async { listener.accept().await.unwrap() }
+		);
+
+		let writter = client.open_uni_stream().await.unwrap();
+		writter.write(&b"ping".to_vec()).await.unwrap();
+		let reader = server.accept_uni_stream().await.unwrap();
+		assert_eq!(reader.read().await.unwrap(), b"ping");
+
+		// 大于一次读取的帧也要完整
+		let local = server.open_bi_stream().await.unwrap();
+		let remote = client.accept_bi_stream().await.unwrap();
+		let large = vec![7u8; 1024 * 1024];
+		local.write(&large).await.unwrap();
+		assert_eq!(remote.read().await.unwrap(), large);
+		remote.write(&b"pong".to_vec()).await.unwrap();
+		assert_eq!(local.read().await.unwrap(), b"pong");
+
+		// 关闭后对方读完即结束
+		writter.close().await.unwrap();
+		assert_eq!(reader.read().await, Err(IOError::ClosedStream));
+
+		drop(client);
+		assert_eq!(remote.read().await, Err(IOError::Disconnected));
+	}
+}
\ No newline at end of file
@@ -0,0 +1,413 @@
+//! UDP transport, every datagram is one serialized `Packet` or `MutPacket` batch.
+//!
+//! Nothing here retransmits, reorders or detects a lost peer: that is left to
+//! `ChunkAck`, `Lack` and the heartbeat of the link. Each peer has a single stream
+//! in each direction, so a block or a batch must fit in `MAX_DATAGRAM_SIZE`.
+//!
+//! Links made by `connect_link` and `accept_link` cut streams into chunks of at most
+//! `MAX_CHUNK_SIZE`, so that chunks are not fragmented by IP.
+use std::{net::SocketAddr, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, Weak}};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use tokio::{net::{ToSocketAddrs, UdpSocket}, select};
+
+use crate::core::{link::{Link, LinkMode, LinkOptions}, strategy::Strategy};
+use super::{io_error, BidirectionalStream, IOError, IOStream, LinkIO, ReaderStream, WritterStream};
+
+/// Largest payload of a single UDP datagram.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+/// Largest chunk payload on a UDP link, a `Chunk` packet then fits in the 1280 bytes every IPv6 path carries.
+pub const MAX_CHUNK_SIZE: usize = 1024;
+
+/// Shrink `stream_chunk_size` to `MAX_CHUNK_SIZE`, for links built on a `UdpIO` by hand.
+pub fn link_options(mut options: LinkOptions) -> LinkOptions {
+	options.stream_chunk_size = options.stream_chunk_size.min(MAX_CHUNK_SIZE);
+	options
+}
+
+// 监听端按对方地址分发数据报
+type Peers = DashMap<SocketAddr, flume::Sender<Vec<u8>>>;
+
+// 同一个对方的所有流共用
+struct Peer {
+	socket: Arc<UdpSocket>,
+	addr: SocketAddr,
+	disconnected: AtomicBool,
+	// 丢掉发送端即可唤醒所有等待者
+	killer: Mutex<Option<flume::Sender<()>>>,
+	killed: flume::Receiver<()>,
+	// 监听端上要注销自己，新的数据报才会成为新连接
+	peers: Option<(Weak<Peers>, flume::Sender<Vec<u8>>)>
+}
+
+impl Peer {
+	fn is_disconnected(&self) -> bool {
+		self.disconnected.load(Ordering::Acquire)
+	}
+
+	fn disconnect(&self) {
+		self.disconnected.store(true, Ordering::Release);
+		if let Ok(mut killer) = self.killer.lock() {
+			killer.take();
+		}
+
+		// 同一地址可能已经是新的连接了
+		if let Some((peers, sender)) = &self.peers
+		&& let Some(peers) = peers.upgrade() {
+			peers.remove_if(&self.addr, |_, current| current.same_channel(sender));
+		}
+	}
+}
+
+/// `LinkIO` over datagrams exchanged with one peer.
+pub struct UdpIO {
+	peer: Arc<Peer>,
+	next_id: AtomicU64,
+	// 只有一个读取流，交出后就没有了
+	inbox: Mutex<Option<flume::Receiver<Vec<u8>>>>
+}
+
+impl UdpIO {
+	/// Talk to `addr` from a fresh local socket.
+	///
+	/// Spawns its reading task on the current tokio runtime.
+	pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, IOError> {
+		let addr = tokio::net::lookup_host(addr)
+			.await
+			.map_err(io_error)?
+			.next()
+			.ok_or(IOError::Disconnected)?;
+		let bind: SocketAddr = match addr {
+			SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+			SocketAddr::V6(_) => ([0u16; 8], 0).into()
+		};
+
+		let socket = Arc::new(UdpSocket::bind(bind).await.map_err(io_error)?);
+		let (sender, inbox) = flume::unbounded();
+		let io = Self::new(socket.clone(), addr, inbox, None);
+
+		tokio::spawn(client_handler(socket, addr, sender, Arc::downgrade(&io.peer)));
+
+		Ok(io)
+	}
+
+	/// Connect to `addr` and put a client `Link` on it.
+	pub async fn connect_link(addr: impl ToSocketAddrs, strategy: Arc<dyn Strategy>, options: LinkOptions) -> Result<Link, IOError> {
+		let io = Self::connect(addr).await?;
+		Ok(Link::with_options(Arc::new(io), LinkMode::Client, strategy, link_options(options)))
+	}
+
+	fn new(socket: Arc<UdpSocket>, addr: SocketAddr, inbox: flume::Receiver<Vec<u8>>, peers: Option<(Weak<Peers>, flume::Sender<Vec<u8>>)>) -> Self {
+		let (killer, killed) = flume::bounded::<()>(0);
+
+		Self {
+			peer: Arc::new(Peer {
+				socket,
+				addr,
+				disconnected: AtomicBool::new(false),
+				killer: Mutex::new(Some(killer)),
+				killed,
+				peers
+			}),
+			next_id: AtomicU64::new(1),
+			inbox: Mutex::new(Some(inbox))
+		}
+	}
+
+	pub fn peer_addr(&self) -> SocketAddr {
+		self.peer.addr
+	}
+
+	/// Stop exchanging datagrams with the peer, all streams fail with `Disconnected`.
+	pub fn disconnect(&self) {
+		self.peer.disconnect();
+	}
+
+	pub fn is_disconnected(&self) -> bool {
+		self.peer.is_disconnected()
+	}
+
+	// 数据报没有连接可言，只能等本端放弃
+	async fn wait_disconnect(&self) -> IOError {
+		if !self.peer.is_disconnected() {
+			let _ = self.peer.killed.recv_async().await;
+		}
+
+		IOError::Disconnected
+	}
+}
+
+impl Drop for UdpIO {
+	fn drop(&mut self) {
+		self.peer.disconnect();
+	}
+}
+
+#[async_trait]
+impl LinkIO for UdpIO {
+	async fn open_uni_stream(&self) -> Result<Arc<dyn WritterStream>, IOError> {
+		if self.peer.is_disconnected() {
+			return Err(IOError::Disconnected);
+		}
+
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		Ok(UdpStream::new(id, &self.peer, None))
+	}
+
+	async fn open_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		Err(IOError::Unknown {
+			code: 0,
+			error: "UDP has no bidirectional streams.".into()
+		})
+	}
+
+	async fn accept_uni_stream(&self) -> Result<Arc<dyn ReaderStream>, IOError> {
+		let inbox = self.inbox.lock().ok().and_then(|mut inbox| inbox.take());
+
+		match inbox {
+			// 对方发来的所有数据报
+			Some(inbox) if !self.peer.is_disconnected() => Ok(UdpStream::new(0, &self.peer, Some(inbox))),
+			_ => Err(self.wait_disconnect().await)
+		}
+	}
+
+	async fn accept_bi_stream(&self) -> Result<Arc<dyn BidirectionalStream>, IOError> {
+		Err(self.wait_disconnect().await)
+	}
+}
+
+/// Demultiplexes datagrams on one socket into a `UdpIO` per peer address.
+pub struct UdpIOListener {
+	socket: Arc<UdpSocket>,
+	incoming: flume::Receiver<(UdpIO, SocketAddr)>,
+	// 监听端不在了，读取任务随之退出
+	_stop: flume::Sender<()>
+}
+
+impl UdpIOListener {
+	/// Spawns its reading task on the current tokio runtime.
+	pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self, IOError> {
+		let socket = Arc::new(UdpSocket::bind(addr).await.map_err(io_error)?);
+		let (incoming_sender, incoming) = flume::unbounded();
+		let (stop, stopped) = flume::bounded::<()>(0);
+
+		tokio::spawn(listener_handler(socket.clone(), incoming_sender, stopped));
+
+		Ok(Self {
+			socket,
+			incoming,
+			_stop: stop
+		})
+	}
+
+	pub fn local_addr(&self) -> Result<SocketAddr, IOError> {
+		self.socket.local_addr().map_err(io_error)
+	}
+
+	/// Wait for the first datagram from a new peer.
+	pub async fn accept(&self) -> Result<(UdpIO, SocketAddr), IOError> {
+		self.incoming.recv_async().await.map_err(|_| IOError::Disconnected)
+	}
+
+	/// Wait for the next peer and put a server `Link` on it.
+	pub async fn accept_link(&self, strategy: Arc<dyn Strategy>, options: LinkOptions) -> Result<(Link, SocketAddr), IOError> {
+		let (io, addr) = self.accept().await?;
+		Ok((Link::with_options(Arc::new(io), LinkMode::Server, strategy, link_options(options)), addr))
+	}
+}
+
+/// The only stream in one direction with a peer.
+pub struct UdpStream {
+	id: u64,
+	peer: Arc<Peer>,
+	inbox: Option<flume::Receiver<Vec<u8>>>,
+	closed: AtomicBool
+}
+
+impl UdpStream {
+	fn new(id: u64, peer: &Arc<Peer>, inbox: Option<flume::Receiver<Vec<u8>>>) -> Arc<Self> {
+		Arc::new(Self {
+			id,
+			peer: peer.clone(),
+			inbox,
+			closed: AtomicBool::new(false)
+		})
+	}
+
+	fn is_over(&self) -> Option<IOError> {
+		if self.peer.is_disconnected() {
+			return Some(IOError::Disconnected);
+		}
+
+		if self.closed.load(Ordering::Acquire) {
+			return Some(IOError::ClosedStream);
+		}
+
+		None
+	}
+}
+
+#[async_trait]
+impl IOStream for UdpStream {
+	fn link_id(&self) -> u64 {
+		self.id
+	}
+
+	async fn close(&self) -> Result<(), IOError> {
+		self.closed.store(true, Ordering::Release);
+		Ok(())
+	}
+
+	async fn is_closed(&self) -> bool {
+		self.is_over().is_some()
+	}
+}
+
+#[async_trait]
+impl ReaderStream for UdpStream {
+	async fn read(&self) -> Result<Vec<u8>, IOError> {
+		let Some(inbox) = &self.inbox else {
+			return Err(IOError::ReadError);
+		};
+
+		if let Some(error) = self.is_over() {
+			return Err(error);
+		}
+
+		select! {
+			result = inbox.recv_async() => result.map_err(|_| IOError::Disconnected),
+			_ = self.peer.killed.recv_async() => Err(IOError::Disconnected)
+		}
+	}
+}
+
+#[async_trait]
+impl WritterStream for UdpStream {
+	async fn write(&self, buffer: &Vec<u8>) -> Result<(), IOError> {
+		if let Some(error) = self.is_over() {
+			return Err(error);
+		}
+
+		if buffer.len() > MAX_DATAGRAM_SIZE {
+			return Err(IOError::WriteError);
+		}
+
+		// 发不出去也和丢包一样，交给上层重传
+		let _ = self.peer.socket.send_to(buffer, self.peer.addr).await;
+		Ok(())
+	}
+}
+
+impl BidirectionalStream for UdpStream {}
+
+// 客户端只收来自对方地址的数据报
+async fn client_handler(socket: Arc<UdpSocket>, addr: SocketAddr, sender: flume::Sender<Vec<u8>>, peer: Weak<Peer>) {
+	let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+
+	loop {
+		let Some(killed) = peer.upgrade().map(|peer| peer.killed.clone()) else {
+			break;
+		};
+
+		let (length, from) = select! {
+			result = socket.recv_from(&mut buffer) => match result {
+				Ok(value) => value,
+				// 比如对方端口不可达，UDP 上不算断开
+				Err(_) => continue
+			},
+			_ = killed.recv_async() => break
+		};
+
+		if from != addr {
+			continue;
+		}
+
+		if sender.send(buffer[..length].to_vec()).is_err() {
+			break;
+		}
+	}
+}
+
+// 按对方地址分发，新地址就是新连接
+async fn listener_handler(socket: Arc<UdpSocket>, incoming: flume::Sender<(UdpIO, SocketAddr)>, stopped: flume::Receiver<()>) {
+	let peers = Arc::new(Peers::new());
+	let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+
+	loop {
+		let (length, from) = select! {
+			result = socket.recv_from(&mut buffer) => match result {
+				Ok(value) => value,
+				Err(_) => continue
+			},
+			_ = stopped.recv_async() => break
+		};
+		let datagram = buffer[..length].to_vec();
+
+		let sender = peers.get(&from).map(|sender| sender.clone());
+		if let Some(sender) = sender {
+			// 对应的 UdpIO 已经不在了
+			if sender.send(datagram).is_err() {
+				peers.remove(&from);
+			}
+			continue;
+		}
+
+		let (sender, inbox) = flume::unbounded();
+		let _ = sender.send(datagram);
+		peers.insert(from, sender.clone());
+
+		let io = UdpIO::new(socket.clone(), from, inbox, Some((Arc::downgrade(&peers), sender)));
+		if incoming.send((io, from)).is_err() {
+			break;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::core::link::LinkOptionsBuilder;
+	use super::*;
+
+	#[tokio::test]
+	async fn split_by_address() {
+		let listener = UdpIOListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let first = UdpIO::connect(addr).await.unwrap();
+		let second = UdpIO::connect(addr).await.unwrap();
+
+		first.open_uni_stream().await.unwrap().write(&b"first".to_vec()).await.unwrap();
+		let (first_server, first_addr) = listener.accept().await.unwrap();
+		second.open_uni_stream().await.unwrap().write(&b"second".to_vec()).await.unwrap();
+		let (second_server, second_addr) = listener.accept().await.unwrap();
+		assert_ne!(first_addr, second_addr);
+
+		// 每个对方地址只看到自己的数据报
+		first.open_uni_stream().await.unwrap().write(&b"again".to_vec()).await.unwrap();
+		let first_reader = first_server.accept_uni_stream().await.unwrap();
+		let second_reader = second_server.accept_uni_stream().await.unwrap();
+		assert_eq!(first_reader.read().await.unwrap(), b"first");
+		assert_eq!(first_reader.read().await.unwrap(), b"again");
+		assert_eq!(second_reader.read().await.unwrap(), b"second");
+
+		// 回复也只送到对应的客户端
+		second_server.open_uni_stream().await.unwrap().write(&b"to second".to_vec()).await.unwrap();
+		first_server.open_uni_stream().await.unwrap().write(&b"to first".to_vec()).await.unwrap();
+		assert_eq!(first.accept_uni_stream().await.unwrap().read().await.unwrap(), b"to first");
+		assert_eq!(second.accept_uni_stream().await.unwrap().read().await.unwrap(), b"to second");
+
+		// 断开后同一地址再来就是新的连接
+		drop(first_server);
+		first.open_uni_stream().await.unwrap().write(&b"new".to_vec()).await.unwrap();
+		let (renewed, renewed_addr) = listener.accept().await.unwrap();
+		assert_eq!(renewed_addr, first_addr);
+		assert_eq!(renewed.accept_uni_stream().await.unwrap().read().await.unwrap(), b"new");
+	}
+
+	#[test]
+	fn chunk_size() {
+		assert_eq!(link_options(LinkOptions::default()).stream_chunk_size, MAX_CHUNK_SIZE);
+
+		let options = LinkOptionsBuilder::default().stream_chunk_size(512).build().unwrap();
+		assert_eq!(link_options(options).stream_chunk_size, 512);
+	}
+}
\ No newline at end of file
@@ -0,0 +1,192 @@
+//! WebSocket transport on tokio-tungstenite, for places that only let WebSocket through.
+//!
+//! Every frame of `mux` is one binary message.
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use futures::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::{TcpListener, TcpStream, ToSocketAddrs},
+	select,
+	sync::{mpsc, Mutex as AsyncMutex},
+	time::timeout
+};
+use tokio_tungstenite::{tungstenite::{client::IntoClientRequest, Message}, WebSocketStream};
+
+use crate::core::{link::{Link, LinkMode, LinkOptions}, strategy::Strategy};
+use super::{io_error, unknown, IOError};
+use super::mux::{MuxIO, MuxIncoming, MuxOutgoing, MuxSide};
+
+/// Connect to a WebSocket server such as `ws://127.0.0.1:8080`.
+pub async fn connect(request: impl IntoClientRequest + Unpin) -> Result<MuxIO, IOError> {
+	let (socket, _) = tokio_tungstenite::connect_async(request).await.map_err(|error| unknown(error.to_string()))?;
+	Ok(from_stream(socket, MuxSide::Client))
+}
+
+/// Wrap an already upgraded WebSocket, the two ends must pick different sides.
+///
+/// Spawns its tasks on the current tokio runtime.
+pub fn from_stream<S>(socket: WebSocketStream<S>, side: MuxSide) -> MuxIO
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	let (sink, stream) = socket.split();
+	let (io, incoming, outgoing) = MuxIO::new(side);
+
+	tokio::spawn(write_handler(sink, outgoing));
+	tokio::spawn(read_handler(stream, incoming));
+
+	io
+}
+
+/// How long a client may take to finish its upgrade before it is dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Accepted = Result<(MuxIO, SocketAddr), IOError>;
+
+/// Accepts TCP clients and upgrades each one to a WebSocket.
+///
+/// Upgrades run in their own tasks, a slow client does not hold up the others.
+pub struct WebSocketListener {
+	addr: SocketAddr,
+	accepted: AsyncMutex<mpsc::Receiver<Accepted>>
+}
+
+impl WebSocketListener {
+	/// Spawns its accept task on the current tokio runtime, which stops once the listener is dropped.
+	pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self, IOError> {
+		let listener = TcpListener::bind(addr).await.map_err(io_error)?;
+		let addr = listener.local_addr().map_err(io_error)?;
+		let (sender, accepted) = mpsc::channel(16);
+		tokio::spawn(accept_handler(listener, sender));
+
+		Ok(Self {
+			addr,
+			accepted: AsyncMutex::new(accepted)
+		})
+	}
+
+	pub fn local_addr(&self) -> Result<SocketAddr, IOError> {
+		Ok(self.addr)
+	}
+
+	/// Wait for the next client to finish its upgrade.
+	pub async fn accept(&self) -> Result<(MuxIO, SocketAddr), IOError> {
+		self.accepted.lock().await.recv().await.unwrap_or(Err(IOError::Disconnected))
+	}
+
+	/// Wait for the next client and put a server `Link` on it.
+	pub async fn accept_link(&self, strategy: Arc<dyn Strategy>, options: LinkOptions) -> Result<(Link, SocketAddr), IOError> {
+		let (io, addr) = self.accept().await?;
+		Ok((Link::with_options(Arc::new(io), LinkMode::Server, strategy, options), addr))
+	}
+}
+
+// 接收 TCP 连接，每个连接单独升级
+async fn accept_handler(listener: TcpListener, accepted: mpsc::Sender<Accepted>) {
+	loop {
+		let result = select! {
+			result = listener.accept() => result,
+			_ = accepted.closed() => break
+		};
+
+		match result {
+			Ok((socket, addr)) => {
+				tokio::spawn(upgrade(socket, addr, accepted.clone()));
+			},
+			Err(error) => {
+				if accepted.send(Err(io_error(error))).await.is_err() {
+					break;
+				}
+			}
+		}
+	}
+}
+
+// 升级失败或太慢的客户端直接放弃，不影响监听
+async fn upgrade(socket: TcpStream, addr: SocketAddr, accepted: mpsc::Sender<Accepted>) {
+	if let Ok(Ok(socket)) = timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::accept_async(socket)).await {
+		let _ = socket.get_ref().set_nodelay(true);
+		let _ = accepted.send(Ok((from_stream(socket, MuxSide::Server), addr))).await;
+	}
+}
+
+// 独占写入端，保证消息不会交错
+async fn write_handler<S>(mut sink: SplitSink<WebSocketStream<S>, Message>, mut outgoing: MuxOutgoing)
+where
+	S: AsyncRead + AsyncWrite + Unpin
+{
+	while let Some(frame) = outgoing.next().await {
+		if sink.send(Message::Binary(frame.into())).await.is_err() {
+			outgoing.disconnect();
+			break;
+		}
+	}
+
+	let _ = sink.close().await;
+}
+
+// 每条二进制消息就是一帧，Ping 和 Pong 由 tungstenite 自己回应
+async fn read_handler<S>(mut stream: SplitStream<WebSocketStream<S>>, incoming: MuxIncoming)
+where
+	S: AsyncRead + AsyncWrite + Unpin
+{
+	while let Some(Ok(message)) = stream.next().await {
+		match message {
+			Message::Binary(frame) if !incoming.handle(&frame) => break,
+			Message::Close(_) => break,
+			_ => {}
+		}
+	}
+
+	incoming.disconnect();
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::io::LinkIO;
+	use super::*;
+
+	#[tokio::test]
+	async fn round_trip() {
+		let listener = WebSocketListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (client, (server, _)) = tokio::join!(
+			async { connect(format!("ws://{addr}")).await.unwrap() },
+			async { listener.accept().await.unwrap() }
+		);
+
+		let writter = client.open_uni_stream().await.unwrap();
+		writter.write(&b"ping".to_vec()).await.unwrap();
+		let reader = server.accept_uni_stream().await.unwrap();
+		assert_eq!(reader.read().await.unwrap(), b"ping");
+
+		let local = server.open_bi_stream().await.unwrap();
+		let remote = client.accept_bi_stream().await.unwrap();
+		local.write(&b"ping".to_vec()).await.unwrap();
+		assert_eq!(remote.read().await.unwrap(), b"ping");
+		remote.write(&b"pong".to_vec()).await.unwrap();
+		assert_eq!(local.read().await.unwrap(), b"pong");
+
+		drop(client);
+		assert_eq!(local.read().await, Err(IOError::Disconnected));
+	}
+
+	#[tokio::test]
+	async fn stalled_handshake() {
+		let listener = WebSocketListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		// 只连上 TCP，不发起升级
+		let _stalled = TcpStream::connect(addr).await.unwrap();
+		let (client, accepted) = tokio::join!(
+			async { connect(format!("ws://{addr}")).await.unwrap() },
+			timeout(Duration::from_secs(1), listener.accept())
+		);
+		let (server, _) = accepted.unwrap().unwrap();
+
+		let writter = client.open_uni_stream().await.unwrap();
+		writter.write(&b"ping".to_vec()).await.unwrap();
+		let reader = server.accept_uni_stream().await.unwrap();
+		assert_eq!(reader.read().await.unwrap(), b"ping");
+	}
+}
\ No newline at end of file